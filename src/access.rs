@@ -0,0 +1,165 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::config::{self, LogFormat};
+use crate::exchange::Exchange;
+use crate::timestamp;
+
+
+/// One line of the JSON access log.
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    request_id: &'a str,
+    client: String,
+    method: Option<&'a str>,
+    target: Option<&'a str>,
+    protocol: Option<&'a str>,
+    host: Option<&'a str>,
+    upstream: Option<String>,
+    status: Option<u16>,
+    bytes_in: u64,
+    bytes_out: u64,
+    duration_ms: f64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// Quotes a field of a Common or Combined Log Format line, logging `"-"` if
+/// there is nothing to log.
+fn quoted(value: Option<&str>) -> String {
+    let value = value.unwrap_or("-");
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn common_line(exchange: &Exchange) -> String {
+    let request_line = exchange.request.as_ref()
+        .map(|request| format!("{} {} {}", request.method, request.target, request.version));
+    let status = match exchange.status {
+        Some(status) => status.to_string(),
+        None => "-".to_owned(),
+    };
+    let bytes = match exchange.bytes_out {
+        0 => "-".to_owned(),
+        bytes => bytes.to_string(),
+    };
+    format!(
+        "{} - - [{}] {} {} {}",
+        exchange.client.ip(),
+        timestamp::common_log(exchange.time),
+        quoted(request_line.as_deref()),
+        status,
+        bytes,
+    )
+}
+
+fn combined_line(exchange: &Exchange) -> String {
+    let request = exchange.request.as_ref();
+    format!(
+        "{} {} {}",
+        common_line(exchange),
+        quoted(request.and_then(|request| request.referer.as_deref())),
+        quoted(request.and_then(|request| request.user_agent.as_deref())),
+    )
+}
+
+fn json_line(exchange: &Exchange) -> String {
+    let request = exchange.request.as_ref();
+    let entry = Entry {
+        time: timestamp::rfc3339(exchange.time),
+        request_id: &exchange.id,
+        client: exchange.client.to_string(),
+        method: request.map(|request| request.method.as_str()),
+        target: request.map(|request| request.target.as_str()),
+        protocol: request.map(|request| request.version.as_str()),
+        host: exchange.host.as_deref(),
+        upstream: exchange.upstream.map(|upstream| upstream.to_string()),
+        status: exchange.status,
+        bytes_in: exchange.bytes_in,
+        bytes_out: exchange.bytes_out,
+        duration_ms: exchange.started.elapsed().as_secs_f64() * 1000.0,
+        referer: request.and_then(|request| request.referer.as_deref()),
+        user_agent: request.and_then(|request| request.user_agent.as_deref()),
+    };
+    // Every field is a plain string or number, so this cannot fail.
+    serde_json::to_string(&entry).unwrap_or_default()
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+
+/// Writes one line per request, to stdout or a file.
+pub struct AccessLog {
+    format: Option<LogFormat>,
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    pub fn new(config: &config::AccessLog) -> io::Result<AccessLog> {
+        let file = match (&config.path, config.enabled) {
+            (Some(path), true) => Some(open(path)?),
+            _ => None,
+        };
+        Ok(AccessLog {
+            format: if config.enabled { Some(config.format) } else { None },
+            path: config.path.clone(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Opens the log file again, so that it can be rotated by moving it away
+    /// and signalling the server.
+    pub fn reopen(&self) -> io::Result<()> {
+        let path = match (&self.path, self.format) {
+            (Some(path), Some(_)) => path,
+            _ => return Ok(()),
+        };
+        let file = open(path)?;
+        match self.file.lock() {
+            Ok(mut current) => *current = Some(file),
+            Err(poisoned) => *poisoned.into_inner() = Some(file),
+        }
+        Ok(())
+    }
+
+    /// Logs the outcome of `exchange`.
+    pub fn record(&self, exchange: &Exchange) {
+        let mut line = match self.format {
+            Some(LogFormat::Common) => common_line(exchange),
+            Some(LogFormat::Combined) => combined_line(exchange),
+            Some(LogFormat::Json) => json_line(exchange),
+            None => return,
+        };
+        line.push('\n');
+
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = match file.as_mut() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => io::stdout().lock().write_all(line.as_bytes()),
+        };
+        if let Err(err) = result {
+            error!("Could not write to the access log: {}", err);
+        }
+    }
+}
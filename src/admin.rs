@@ -0,0 +1,55 @@
+use std::io;
+use std::sync::Arc;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::ClientError;
+use crate::http;
+use crate::timeout::TimeoutStream;
+use crate::Server;
+
+
+fn response(status: u16, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, http::reason_phrase(status), content_type, body.len(), body,
+    ).into_bytes()
+}
+
+/// Answers a single request to the admin listener, then closes the connection.
+async fn handle(stream: TcpStream, server: &Server) -> Result<(), ClientError> {
+    let timeouts = &server.config.timeouts;
+    let mut client = BufReader::new(TimeoutStream::new(stream, timeouts.header(), timeouts.write()));
+    let request = match http::read_head(&mut client).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let path = request.target().split('?').next().unwrap_or("");
+    let response = match (request.method(), path) {
+        ("GET", "/metrics") => {
+            let metrics = server.metrics.render(&server.connections);
+            response(200, "text/plain; version=0.0.4; charset=utf-8", &metrics)
+        },
+        (_, "/metrics") => response(405, "text/plain; charset=utf-8", "Method Not Allowed\n"),
+        _ => response(404, "text/plain; charset=utf-8", "Not Found\n"),
+    };
+    match client.get_mut().write_all(&response).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ClientError::IOError(err)),
+    }
+}
+
+/// Serves the admin endpoints to connections on `listener`.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &server).await {
+                debug!("Admin request failed: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,909 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::logging::Level;
+
+
+/// The configuration file read when `--config` is not given, if it exists.
+const DEFAULT_CONFIG_PATH: &str = "router.toml";
+
+const USAGE: &str = "\
+Usage: router [OPTIONS]
+
+Options:
+  -c, --config <PATH>          Read configuration from PATH (default: router.toml, if present)
+  -b, --bind <ADDRESS>         Address to listen on, with an optional port; may be repeated
+  -p, --port <PORT>            Port to listen on where a bind address has none
+  -r, --responses <DIR>        Directory holding the response templates
+      --connect-timeout <SECS> Timeout for connecting to upstreams
+      --header-timeout <SECS>  Time allowed for a client to send a request head
+      --body-timeout <SECS>    Timeout for reads of a request body
+      --first-byte-timeout <SECS>
+                               Time allowed for an upstream to start responding
+      --read-timeout <SECS>    Timeout for other reads from clients and upstreams
+      --write-timeout <SECS>   Timeout for writes to clients and upstreams
+      --idle-timeout <SECS>    How long to keep idle client connections open
+      --max-connections <N>    Client connections handled at once; 0 for no limit
+      --when-full <ACTION>     queue or reject connections over the limit
+      --admin <ADDRESS>        Address to serve /metrics on; off by default
+      --access-log <PATH>      File to write the access log to, or - for stdout
+      --access-log-format <FORMAT>
+                               One of common, combined or json
+  -l, --log-level <LEVEL>      One of error, warn, info or debug
+  -h, --help                   Print this message";
+
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(PathBuf, io::Error),
+    ParseError(PathBuf, toml::de::Error),
+    InvalidArgument(String),
+    Invalid(String),
+    HelpRequested,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::IOError(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::ParseError(path, err) => write!(f, "could not parse {}: {}", path.display(), err),
+            ConfigError::InvalidArgument(message) => write!(f, "{}\n\n{}", message, USAGE),
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {}", message),
+            ConfigError::HelpRequested => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+
+/// An address to listen on. Without a port, the configured `port` is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum BindAddress {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl std::str::FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return Ok(BindAddress::Socket(address));
+        }
+        // Allow IPv6 addresses to be bracketed even without a port.
+        let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => Ok(BindAddress::Ip(ip)),
+            Err(_) => Err(format!("invalid bind address `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for BindAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<BindAddress>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(BindAddress),
+        Many(Vec<BindAddress>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(address) => Ok(vec![address]),
+        OneOrMany::Many(addresses) => Ok(addresses),
+    }
+}
+
+
+/// An IP network in CIDR notation, such as `10.0.0.0/8`, or a single
+/// address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address = match address.parse::<IpAddr>() {
+            Ok(address) => address.to_canonical(),
+            Err(_) => return Err(format!("invalid network `{}`", s)),
+        };
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(str::parse::<u8>) {
+            None => max_prefix,
+            Some(Ok(prefix)) if prefix <= max_prefix => prefix,
+            Some(_) => return Err(format!("invalid prefix length in `{}`", s)),
+        };
+        Ok(Network { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+
+/// A regular expression, compiled when the configuration is loaded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match Regex::new(&s) {
+            Ok(regex) => Ok(Pattern(regex)),
+            Err(err) => Err(format!("invalid pattern `{}`: {}", s, err)),
+        }
+    }
+}
+
+impl std::ops::Deref for Pattern {
+    type Target = Regex;
+
+    fn deref(&self) -> &Regex {
+        &self.0
+    }
+}
+
+
+/// Socket timeouts, in seconds. A value of zero disables the timeout.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub connect: u64,
+    /// For the whole head of a request.
+    pub header: u64,
+    pub body: u64,
+    /// From sending a request until the upstream's response head arrives.
+    pub first_byte: u64,
+    pub read: u64,
+    pub write: u64,
+    pub idle: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: 10,
+            header: 20,
+            body: 30,
+            first_byte: 30,
+            read: 60,
+            write: 60,
+            idle: 15,
+        }
+    }
+}
+
+fn seconds(value: u64) -> Option<Duration> {
+    match value {
+        0 => None,
+        value => Some(Duration::from_secs(value)),
+    }
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Option<Duration> {
+        seconds(self.connect)
+    }
+
+    pub fn header(&self) -> Option<Duration> {
+        seconds(self.header)
+    }
+
+    pub fn body(&self) -> Option<Duration> {
+        seconds(self.body)
+    }
+
+    pub fn first_byte(&self) -> Option<Duration> {
+        seconds(self.first_byte)
+    }
+
+    pub fn read(&self) -> Option<Duration> {
+        seconds(self.read)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        seconds(self.write)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        seconds(self.idle)
+    }
+}
+
+
+/// Reuse of idle keep-alive connections to upstreams.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pool {
+    /// How many idle connections to keep per upstream; zero disables reuse.
+    pub max_idle_per_host: usize,
+    /// How long, in seconds, an idle connection is kept; zero keeps it until
+    /// the upstream closes it.
+    pub idle_timeout: u64,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool {
+            max_idle_per_host: 8,
+            idle_timeout: 30,
+        }
+    }
+}
+
+impl Pool {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        seconds(self.idle_timeout)
+    }
+}
+
+
+/// What to do with a client connection beyond the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    /// Leave it waiting until another connection finishes.
+    Queue,
+    /// Answer it straight away with `503 Service Unavailable`.
+    Reject,
+}
+
+impl std::str::FromStr for WhenFull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "queue" => Ok(WhenFull::Queue),
+            "reject" => Ok(WhenFull::Reject),
+            _ => Err(format!("unknown action `{}`", s)),
+        }
+    }
+}
+
+/// Admission control for client connections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Connections {
+    /// How many connections are handled at once; zero means no limit.
+    pub max: usize,
+    pub when_full: WhenFull,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections {
+            max: 1024,
+            when_full: WhenFull::Queue,
+        }
+    }
+}
+
+
+/// The layout of access log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The Common Log Format.
+    Common,
+    /// The Common Log Format followed by the referer and user agent.
+    Combined,
+    /// One JSON object per line, with every recorded detail.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format `{}`", s)),
+        }
+    }
+}
+
+/// The log of every request handled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLog {
+    pub enabled: bool,
+    pub format: LogFormat,
+    /// The file to append to; stdout if unset.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog {
+            enabled: true,
+            format: LogFormat::Combined,
+            path: None,
+        }
+    }
+}
+
+
+/// How a backend picks which of its servers to send a request to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Each server in turn.
+    #[default]
+    RoundRobin,
+    /// The server with the fewest requests in progress.
+    LeastConnections,
+    /// The less busy of two servers picked at random.
+    RandomTwoChoices,
+    /// The same server for the same client IP, or `hash_header` value.
+    ConsistentHash,
+}
+
+/// Periodic requests to each server of a backend, which take servers that
+/// fail them out of rotation until they pass again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    pub path: String,
+    /// Seconds between checks.
+    pub interval: u64,
+    /// Seconds a check may take.
+    pub timeout: u64,
+    pub expected_status: u16,
+    /// Consecutive passes that bring a server back into rotation.
+    pub rise: u32,
+    /// Consecutive failures that take a server out of rotation.
+    pub fall: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            path: "/".to_owned(),
+            interval: 10,
+            timeout: 2,
+            expected_status: 200,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.max(1))
+    }
+}
+
+/// Ejection of servers that fail live requests, by refusing connections or
+/// answering with 5xx responses.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierDetection {
+    /// Consecutive failures after which a server is ejected.
+    pub max_failures: u32,
+    /// Seconds an ejected server is left out of rotation.
+    pub ejection_time: u64,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            max_failures: 5,
+            ejection_time: 30,
+        }
+    }
+}
+
+/// A named group of upstream servers that routes send requests to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backend {
+    /// `host:port` of each server, resolved when a request is sent or the
+    /// server health checked. The port defaults to 80.
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// The request header to hash under consistent hashing, rather than the
+    /// client's IP address.
+    pub hash_header: Option<String>,
+    pub health_check: Option<HealthCheck>,
+    pub outlier_detection: Option<OutlierDetection>,
+}
+
+/// What a header rule does to the headers it names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderAction {
+    /// Adds a header, alongside any of the same name.
+    Add,
+    /// Replaces any headers of the name with one.
+    Set,
+    Remove,
+    /// Replaces matches of `pattern` in the headers' values.
+    Replace,
+}
+
+/// A change made to the headers of each request or response.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    pub name: String,
+    /// The value to add or set, or the replacement for `pattern`, which may
+    /// refer to its groups as `$1` or `${name}`.
+    pub value: Option<String>,
+    pub pattern: Option<Pattern>,
+}
+
+/// Rules applied in order to the headers of requests on their way to an
+/// upstream, and of its responses on their way back.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Headers {
+    pub request: Vec<HeaderRule>,
+    pub response: Vec<HeaderRule>,
+}
+
+/// Changes made to the path of each request on a route before it is
+/// forwarded: the prefix is stripped, then added, then the pattern replaced.
+/// The query string is kept as it is.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathRewrite {
+    /// Removed from the start of paths, on a segment boundary.
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    pub pattern: Option<Pattern>,
+    /// What replaces matches of `pattern`, which may refer to its groups as
+    /// `$1` or `${name}`.
+    pub replacement: Option<String>,
+}
+
+impl PathRewrite {
+    fn validate(&self) -> Result<(), ConfigError> {
+        for prefix in self.strip_prefix.iter().chain(&self.add_prefix) {
+            if !prefix.starts_with('/') {
+                return Err(ConfigError::Invalid(format!("path prefix `{}` does not start with `/`", prefix)));
+            }
+        }
+        for path in self.add_prefix.iter().chain(&self.replacement) {
+            if path.contains(|c: char| c.is_whitespace() || c.is_control()) {
+                return Err(ConfigError::Invalid(format!("path rewrite `{}` contains whitespace", path.escape_debug())));
+            }
+        }
+        match (&self.pattern, &self.replacement) {
+            (Some(_), None) => Err(ConfigError::Invalid("path rewrite pattern has no `replacement`".to_owned())),
+            (None, Some(_)) => Err(ConfigError::Invalid("path rewrite replacement has no `pattern`".to_owned())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Answers requests on a route with a redirect rather than forwarding them.
+/// The path, after the route's `rewrite`, and the query string are carried
+/// over.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Redirect {
+    /// One of 301, 302, 307 or 308.
+    pub status: u16,
+    /// The scheme to redirect to; the request's own if unset.
+    pub scheme: Option<String>,
+    /// The host, with an optional port, to redirect to; the request's own if
+    /// unset.
+    pub host: Option<String>,
+}
+
+impl Default for Redirect {
+    fn default() -> Self {
+        Redirect {
+            status: 301,
+            scheme: None,
+            host: None,
+        }
+    }
+}
+
+impl Redirect {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if ![301, 302, 307, 308].contains(&self.status) {
+            return Err(ConfigError::Invalid(format!("redirect status {} is not 301, 302, 307 or 308", self.status)));
+        }
+        if let Some(scheme) = self.scheme.as_deref().filter(|scheme| {
+            !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }) {
+            return Err(ConfigError::Invalid(format!("invalid redirect scheme `{}`", scheme)));
+        }
+        if let Some(host) = self.host.as_deref().filter(|host| {
+            host.is_empty() || host.contains(|c: char| c.is_whitespace() || c.is_control() || "/?#@".contains(c))
+        }) {
+            return Err(ConfigError::Invalid(format!("invalid redirect host `{}`", host.escape_debug())));
+        }
+        Ok(())
+    }
+}
+
+/// Sends requests for a virtual host and path prefix to a backend, or
+/// redirects them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// The host name to match, or `*.domain` for any subdomain; any host if
+    /// unset.
+    pub host: Option<String>,
+    /// The path prefix to match, on segment boundaries.
+    #[serde(default = "default_route_path")]
+    pub path: String,
+    /// The name of the backend to use.
+    pub backend: Option<String>,
+    pub redirect: Option<Redirect>,
+    /// Rules applied after the global ones.
+    #[serde(default)]
+    pub headers: Headers,
+    #[serde(default)]
+    pub rewrite: PathRewrite,
+}
+
+fn default_route_path() -> String {
+    "/".to_owned()
+}
+
+/// Headers that header rules may not touch: the proxy relies on them to
+/// frame messages and route requests, after the rules have run.
+const PROTECTED_HEADERS: [&str; 4] = ["Connection", "Content-Length", "Host", "Transfer-Encoding"];
+
+impl HeaderRule {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let is_token = !self.name.is_empty()
+            && self.name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+        if !is_token {
+            return Err(ConfigError::Invalid(format!("invalid header name `{}`", self.name)));
+        }
+        if PROTECTED_HEADERS.iter().any(|name| name.eq_ignore_ascii_case(&self.name)) {
+            return Err(ConfigError::Invalid(format!("header rules cannot change `{}`", self.name)));
+        }
+        // Line breaks would let a value add headers of its own.
+        if let Some(value) = self.value.as_deref().filter(|value| value.contains(['\r', '\n'])) {
+            return Err(ConfigError::Invalid(format!("header value `{}` contains a line break", value.escape_debug())));
+        }
+
+        let missing = match self.action {
+            HeaderAction::Add | HeaderAction::Set if self.value.is_none() => Some("value"),
+            HeaderAction::Replace if self.value.is_none() => Some("value"),
+            HeaderAction::Replace if self.pattern.is_none() => Some("pattern"),
+            _ => None,
+        };
+        match missing {
+            Some(field) => Err(ConfigError::Invalid(format!("header rule for `{}` needs a `{}`", self.name, field))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Headers {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.request.iter().chain(&self.response).try_for_each(HeaderRule::validate)
+    }
+}
+
+
+/// Headers telling upstreams about the client and the proxies in between.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Forwarding {
+    /// Clients whose own `X-Forwarded-*` and `Forwarded` headers are kept and
+    /// added to; from anyone else they are replaced.
+    pub trusted_proxies: Vec<Network>,
+    /// The name this proxy gives itself in `Via` headers.
+    pub via: String,
+}
+
+impl Default for Forwarding {
+    fn default() -> Self {
+        Forwarding {
+            trusted_proxies: Vec::new(),
+            via: "router".to_owned(),
+        }
+    }
+}
+
+impl Forwarding {
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|network| network.contains(ip))
+    }
+}
+
+
+/// The listener for operational endpoints such as `/metrics`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    /// The address to listen on; no admin listener is started if unset.
+    pub bind: Option<SocketAddr>,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<BindAddress>,
+    pub port: u16,
+    pub responses: PathBuf,
+    pub timeouts: Timeouts,
+    pub pool: Pool,
+    pub connections: Connections,
+    pub access_log: AccessLog,
+    pub admin: Admin,
+    pub forwarding: Forwarding,
+    pub headers: Headers,
+    pub backends: BTreeMap<String, Backend>,
+    pub routes: Vec<Route>,
+    pub log_level: Level,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![BindAddress::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST))],
+            port: 8080,
+            responses: PathBuf::from("responses"),
+            timeouts: Timeouts::default(),
+            pool: Pool::default(),
+            connections: Connections::default(),
+            access_log: AccessLog::default(),
+            admin: Admin::default(),
+            forwarding: Forwarding::default(),
+            headers: Headers::default(),
+            backends: BTreeMap::new(),
+            routes: Vec::new(),
+            log_level: Level::Info,
+        }
+    }
+}
+
+impl Config {
+    /// Every socket address to listen on.
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        self.bind.iter()
+            .map(|address| match *address {
+                BindAddress::Ip(ip) => SocketAddr::new(ip, self.port),
+                BindAddress::Socket(address) => address,
+            })
+            .collect()
+    }
+
+    /// Reads the configuration file at `path`.
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(ConfigError::IOError(path.to_owned(), err)),
+        };
+        match toml::from_str(&contents) {
+            Ok(config) => Ok(config),
+            Err(err) => Err(ConfigError::ParseError(path.to_owned(), err)),
+        }
+    }
+
+    /// Builds the configuration from the command line: the configuration
+    /// file, if any, with individual options applied on top of it.
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let mut config_path = None;
+        let mut binds = Vec::new();
+        let mut overrides = Vec::new();
+
+        let mut args = args.skip(1);
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value.to_owned())),
+                _ => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                return Err(ConfigError::HelpRequested);
+            }
+
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(ConfigError::InvalidArgument(format!("missing value for `{}`", flag))),
+            };
+            if flag == "-c" || flag == "--config" {
+                config_path = Some(PathBuf::from(value));
+            } else if flag == "-b" || flag == "--bind" {
+                match value.parse::<BindAddress>() {
+                    Ok(address) => binds.push(address),
+                    Err(message) => return Err(ConfigError::InvalidArgument(message)),
+                }
+            } else {
+                overrides.push((flag, value));
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Config::default(),
+        };
+        // Bind addresses given on the command line replace those in the file.
+        if !binds.is_empty() {
+            config.bind = binds;
+        }
+        for (flag, value) in overrides {
+            config.apply_override(&flag, &value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks what deserialization alone cannot.
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, backend) in &self.backends {
+            if backend.servers.is_empty() {
+                return Err(ConfigError::Invalid(format!("backend `{}` has no servers", name)));
+            }
+        }
+        for route in &self.routes {
+            match (&route.backend, &route.redirect) {
+                (Some(backend), None) if !self.backends.contains_key(backend) => {
+                    return Err(ConfigError::Invalid(format!("route to unknown backend `{}`", backend)));
+                },
+                (Some(_), None) => (),
+                (None, Some(redirect)) => redirect.validate()?,
+                _ => return Err(ConfigError::Invalid(format!("route for `{}` needs either a backend or a redirect", route.path))),
+            }
+            if !route.path.starts_with('/') {
+                return Err(ConfigError::Invalid(format!("route path `{}` does not start with `/`", route.path)));
+            }
+            route.headers.validate()?;
+            route.rewrite.validate()?;
+        }
+        self.headers.validate()
+    }
+
+    fn apply_override(&mut self, flag: &str, value: &str) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+            match value.parse::<T>() {
+                Ok(value) => Ok(value),
+                Err(_) => Err(ConfigError::InvalidArgument(format!("invalid value `{}` for `{}`", value, flag))),
+            }
+        }
+
+        match flag {
+            "-p" | "--port" => self.port = parse(flag, value)?,
+            "-r" | "--responses" => self.responses = PathBuf::from(value),
+            "--connect-timeout" => self.timeouts.connect = parse(flag, value)?,
+            "--header-timeout" => self.timeouts.header = parse(flag, value)?,
+            "--body-timeout" => self.timeouts.body = parse(flag, value)?,
+            "--first-byte-timeout" => self.timeouts.first_byte = parse(flag, value)?,
+            "--read-timeout" => self.timeouts.read = parse(flag, value)?,
+            "--write-timeout" => self.timeouts.write = parse(flag, value)?,
+            "--idle-timeout" => self.timeouts.idle = parse(flag, value)?,
+            "--max-connections" => self.connections.max = parse(flag, value)?,
+            "--when-full" => self.connections.when_full = parse(flag, value)?,
+            "--admin" => self.admin.bind = Some(parse(flag, value)?),
+            "--access-log" => self.access_log.path = match value {
+                "-" => None,
+                path => Some(PathBuf::from(path)),
+            },
+            "--access-log-format" => self.access_log.format = parse(flag, value)?,
+            "-l" | "--log-level" => self.log_level = parse(flag, value)?,
+            _ => return Err(ConfigError::InvalidArgument(format!("unknown option `{}`", flag))),
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(text: &str) -> Result<(), ConfigError> {
+        toml::from_str::<Config>(text).unwrap().validate()
+    }
+
+    fn contains(network: &str, ip: &str) -> bool {
+        network.parse::<Network>().unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn networks_match_their_prefix() {
+        assert!(contains("10.0.0.0/8", "10.255.1.2"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.7/32", "192.168.1.7"));
+        assert!(!contains("192.168.1.7/32", "192.168.1.8"));
+        assert!(contains("192.168.1.7", "192.168.1.7"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("::1/128", "::1"));
+    }
+
+    #[test]
+    fn networks_compare_ipv4_mapped_addresses_as_ipv4() {
+        assert!(contains("127.0.0.0/8", "::ffff:127.0.0.1"));
+        assert!(contains("::ffff:10.0.0.1", "10.0.0.1"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        for network in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0/8", "example.com", "10.0.0.0/-1"] {
+            assert!(network.parse::<Network>().is_err(), "accepted `{}`", network);
+        }
+    }
+
+    #[test]
+    fn header_rules_are_validated() {
+        assert!(validate("[[headers.response]]\naction = \"remove\"\nname = \"Server\"").is_ok());
+        assert!(validate("[[headers.response]]\naction = \"set\"\nname = \"X-A\"").is_err());
+        assert!(validate("[[headers.response]]\naction = \"replace\"\nname = \"X-A\"\nvalue = \"b\"").is_err());
+        assert!(validate("[[headers.request]]\naction = \"add\"\nname = \"X A\"\nvalue = \"b\"").is_err());
+        assert!(validate("[[headers.request]]\naction = \"add\"\nname = \"X-A\"\nvalue = \"b\\r\\nX-B: c\"").is_err());
+    }
+
+    #[test]
+    fn header_rules_cannot_change_framing_or_routing_headers() {
+        for name in ["Content-Length", "transfer-encoding", "Connection", "HOST"] {
+            for action in ["add", "set", "remove"] {
+                let text = format!("[[headers.request]]\naction = \"{}\"\nname = \"{}\"\nvalue = \"1\"", action, name);
+                assert!(validate(&text).is_err(), "allowed {} of {}", action, name);
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+
+
+#[derive(Debug)]
+pub enum ClientError {
+    Utf8Error(std::string::FromUtf8Error),
+    IOError(std::io::Error),
+    WriteError(std::io::Error),
+    ParseIntError(std::num::ParseIntError),
+    MalformedMessage(&'static str),
+    InvalidHost(&'static str),
+    NoHostFound,
+    UnknownHost(String),
+    NoRoute(String),
+    SelfRequested,
+    Overloaded,
+    RequestTimeout,
+    UpstreamConnectFailed(std::io::Error),
+    UpstreamTimeout,
+    BadGateway(Box<ClientError>),
+    ResponseInterrupted(Box<ClientError>),
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+impl ClientError {
+    /// Reclassifies an error that occurred while reading from the client.
+    pub fn from_client(err: ClientError) -> ClientError {
+        match err {
+            ClientError::IOError(ref e) if is_timeout(e) => ClientError::RequestTimeout,
+            err => err,
+        }
+    }
+
+    /// Reclassifies an error that occurred while talking to the upstream.
+    pub fn from_upstream(err: ClientError) -> ClientError {
+        match err {
+            ClientError::IOError(ref e) | ClientError::WriteError(ref e) if is_timeout(e) => ClientError::UpstreamTimeout,
+            ClientError::UpstreamConnectFailed(ref e) if is_timeout(e) => ClientError::UpstreamTimeout,
+            err @ (ClientError::UpstreamConnectFailed(_)
+                | ClientError::UpstreamTimeout
+                | ClientError::BadGateway(_)
+                | ClientError::ResponseInterrupted(_)) => err,
+            err => ClientError::BadGateway(Box::new(err)),
+        }
+    }
+
+    /// A short name for the kind of error, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientError::Utf8Error(_) => "utf8",
+            ClientError::IOError(_) => "io",
+            ClientError::WriteError(_) => "write",
+            ClientError::ParseIntError(_) => "parse_int",
+            ClientError::MalformedMessage(_) => "malformed_message",
+            ClientError::InvalidHost(_) => "invalid_host",
+            ClientError::NoHostFound => "no_host_found",
+            ClientError::UnknownHost(_) => "unknown_host",
+            ClientError::NoRoute(_) => "no_route",
+            ClientError::SelfRequested => "self_requested",
+            ClientError::Overloaded => "overloaded",
+            ClientError::RequestTimeout => "request_timeout",
+            ClientError::UpstreamConnectFailed(_) => "upstream_connect_failed",
+            ClientError::UpstreamTimeout => "upstream_timeout",
+            ClientError::BadGateway(_) => "bad_gateway",
+            ClientError::ResponseInterrupted(_) => "response_interrupted",
+        }
+    }
+
+    /// The status of the error response to send the client, if one can
+    /// still be sent.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ClientError::Utf8Error(_)
+            | ClientError::ParseIntError(_)
+            | ClientError::MalformedMessage(_)
+            | ClientError::InvalidHost(_)
+            | ClientError::NoHostFound => Some(400),
+            ClientError::UnknownHost(_) | ClientError::NoRoute(_) => Some(404),
+            ClientError::SelfRequested => Some(508),
+            ClientError::Overloaded => Some(503),
+            ClientError::RequestTimeout => Some(408),
+            ClientError::UpstreamConnectFailed(_) | ClientError::BadGateway(_) => Some(502),
+            ClientError::UpstreamTimeout => Some(504),
+            // The client connection itself failed, or part of the response
+            // has already been sent.
+            ClientError::IOError(_) | ClientError::WriteError(_) | ClientError::ResponseInterrupted(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Utf8Error(err) => write!(f, "invalid UTF-8: {}", err),
+            ClientError::IOError(err) => write!(f, "I/O error: {}", err),
+            ClientError::WriteError(err) => write!(f, "write error: {}", err),
+            ClientError::ParseIntError(err) => write!(f, "invalid number: {}", err),
+            ClientError::MalformedMessage(reason) => write!(f, "malformed HTTP message: {}", reason),
+            ClientError::InvalidHost(reason) => write!(f, "invalid host: {}", reason),
+            ClientError::NoHostFound => write!(f, "no host found"),
+            ClientError::UnknownHost(host) => write!(f, "could not resolve {}", host),
+            ClientError::NoRoute(host) => write!(f, "no route for {}", host),
+            ClientError::SelfRequested => write!(f, "request loops back to this server"),
+            ClientError::Overloaded => write!(f, "too many connections"),
+            ClientError::RequestTimeout => write!(f, "client timed out sending the request"),
+            ClientError::UpstreamConnectFailed(err) => write!(f, "could not connect to upstream: {}", err),
+            ClientError::UpstreamTimeout => write!(f, "upstream timed out"),
+            ClientError::BadGateway(err) => write!(f, "upstream failed: {}", err),
+            ClientError::ResponseInterrupted(err) => write!(f, "response interrupted: {}", err),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::http::Head;
+
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Generates an identifier that is unique within this process and unlikely
+/// to repeat across restarts.
+fn next_id() -> String {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    let seed = *SEED.get_or_init(|| match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_nanos() as u64,
+        Err(_) => 0,
+    });
+    format!("{:016x}", seed.wrapping_add(NEXT_ID.fetch_add(1, Ordering::Relaxed)))
+}
+
+
+/// The parts of a request's start line and headers that are reported on.
+pub struct RequestSummary {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+
+/// What is known about a request as it is processed, for reporting on it
+/// once it completes or fails.
+pub struct Exchange {
+    pub id: String,
+    pub client: SocketAddr,
+    pub time: SystemTime,
+    pub started: Instant,
+    pub request: Option<RequestSummary>,
+    pub host: Option<String>,
+    pub accept: Option<String>,
+    pub upstream: Option<SocketAddr>,
+    /// The configured server `upstream` belongs to, in reverse-proxy mode.
+    pub server: Option<String>,
+    pub status: Option<u16>,
+    /// Body bytes received from the client.
+    pub bytes_in: u64,
+    /// Body bytes sent to the client.
+    pub bytes_out: u64,
+}
+
+impl Exchange {
+    pub fn start(client: SocketAddr) -> Exchange {
+        Exchange {
+            id: next_id(),
+            client,
+            time: SystemTime::now(),
+            started: Instant::now(),
+            request: None,
+            host: None,
+            accept: None,
+            upstream: None,
+            server: None,
+            status: None,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    /// Records the details of the request once its head has been read.
+    pub fn set_request(&mut self, request: &Head) {
+        let header = |name| request.header_values(name).next().map(str::to_owned);
+        self.request = Some(RequestSummary {
+            method: request.method().to_owned(),
+            target: request.target().to_owned(),
+            version: request.version().to_owned(),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        });
+        self.accept = header("Accept");
+    }
+}
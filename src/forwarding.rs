@@ -0,0 +1,120 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::Forwarding;
+use crate::http::Head;
+
+
+/// The protocol clients use to reach this proxy.
+pub const PROTOCOL: &str = "http";
+
+/// Formats a value for a `Forwarded` header parameter, quoting it unless it
+/// is a plain token (RFC 7239 §4).
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// The client address as a `Forwarded` node, with IPv6 addresses bracketed
+/// (RFC 7239 §6).
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => forwarded_value(&format!("[{}]", ip)),
+    }
+}
+
+/// Adds this proxy to the `Via` header of a request or response
+/// (RFC 7230 §5.7.1).
+pub fn add_via(head: &mut Head, config: &Forwarding) {
+    let version = head.version().strip_prefix("HTTP/").unwrap_or("1.1").to_owned();
+    head.append_header("Via", &format!("{} {}", version, config.via));
+}
+
+/// Adds the headers telling the upstream who the client is and what it asked
+/// for. Existing values are only kept from trusted proxies, since anyone
+/// else could have forged them.
+pub fn add_request_headers(request: &mut Head, client: SocketAddr, config: &Forwarding) {
+    let client_ip = client.ip().to_canonical();
+    let host = request.header_values("Host").next().map(str::to_owned);
+
+    if !config.is_trusted(client_ip) {
+        for name in ["X-Forwarded-For", "X-Forwarded-Host", "X-Forwarded-Proto", "Forwarded"] {
+            request.remove_header(name);
+        }
+    }
+
+    request.append_header("X-Forwarded-For", &client_ip.to_string());
+    if let Some(host) = &host {
+        if request.header_values("X-Forwarded-Host").next().is_none() {
+            request.set_header("X-Forwarded-Host", host);
+        }
+    }
+    if request.header_values("X-Forwarded-Proto").next().is_none() {
+        request.set_header("X-Forwarded-Proto", PROTOCOL);
+    }
+
+    let mut forwarded = format!("for={}", forwarded_node(client_ip));
+    if let Some(host) = &host {
+        forwarded.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    forwarded.push_str(&format!(";proto={}", PROTOCOL));
+    request.append_header("Forwarded", &forwarded);
+
+    add_via(request, config);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::http;
+
+    const REQUEST: &str = "GET / HTTP/1.1\r\nHost: www.example.com\r\nX-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Host: forged.example\r\nX-Forwarded-Proto: https\r\nForwarded: for=203.0.113.7\r\n\r\n";
+
+    fn forward(request: &str, client: &str, trusted_proxies: &str) -> Head {
+        let config: Forwarding = toml::from_str(&format!("trusted_proxies = {}", trusted_proxies)).unwrap();
+        let mut request = http::parse_head(request).unwrap();
+        add_request_headers(&mut request, client.parse().unwrap(), &config);
+        request
+    }
+
+    fn values<'a>(head: &'a Head, name: &'a str) -> Vec<&'a str> {
+        head.header_values(name).collect()
+    }
+
+    #[test]
+    fn headers_from_untrusted_clients_are_replaced() {
+        let request = forward(REQUEST, "198.51.100.2:5000", "[\"10.0.0.0/8\"]");
+        assert_eq!(values(&request, "X-Forwarded-For"), ["198.51.100.2"]);
+        assert_eq!(values(&request, "X-Forwarded-Host"), ["www.example.com"]);
+        assert_eq!(values(&request, "X-Forwarded-Proto"), ["http"]);
+        assert_eq!(values(&request, "Forwarded"), ["for=198.51.100.2;host=www.example.com;proto=http"]);
+        assert_eq!(values(&request, "Via"), ["1.1 router"]);
+    }
+
+    #[test]
+    fn headers_from_trusted_proxies_are_added_to() {
+        let request = forward(REQUEST, "10.1.2.3:5000", "[\"10.0.0.0/8\"]");
+        assert_eq!(values(&request, "X-Forwarded-For"), ["203.0.113.7, 10.1.2.3"]);
+        assert_eq!(values(&request, "X-Forwarded-Host"), ["forged.example"]);
+        assert_eq!(values(&request, "X-Forwarded-Proto"), ["https"]);
+        assert_eq!(values(&request, "Forwarded"), ["for=203.0.113.7, for=10.1.2.3;host=www.example.com;proto=http"]);
+    }
+
+    #[test]
+    fn ipv6_clients_are_quoted_in_forwarded() {
+        let request = forward("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n", "[2001:db8::1]:5000", "[]");
+        assert_eq!(values(&request, "X-Forwarded-For"), ["2001:db8::1"]);
+        assert_eq!(values(&request, "Forwarded"), ["for=\"[2001:db8::1]\";host=\"[::1]:8080\";proto=http"]);
+
+        // IPv4-mapped addresses are reported as IPv4.
+        let request = forward("GET / HTTP/1.1\r\n\r\n", "[::ffff:192.0.2.1]:5000", "[]");
+        assert_eq!(values(&request, "X-Forwarded-For"), ["192.0.2.1"]);
+        assert_eq!(values(&request, "Forwarded"), ["for=192.0.2.1;proto=http"]);
+    }
+}
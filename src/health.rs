@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::config::HealthCheck;
+use crate::http;
+use crate::routing::Upstream;
+
+
+/// Requests the health check path from `upstream` and checks the status of
+/// the response.
+async fn check(upstream: &Upstream, health_check: &HealthCheck) -> Result<(), String> {
+    // Resolved as requests resolve it, so a server without a port is
+    // checked on port 80 too.
+    let address = match crate::split_address(&upstream.address, Some(80)) {
+        Ok(address) => address,
+        Err(err) => return Err(err.to_string()),
+    };
+    let address = match crate::dns_lookup(address).await {
+        Ok(addresses) => addresses[0],
+        Err(err) => return Err(format!("could not resolve: {}", err)),
+    };
+    let mut stream = match TcpStream::connect(address).await {
+        Ok(stream) => BufReader::new(stream),
+        Err(err) => return Err(format!("could not connect: {}", err)),
+    };
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: router-health-check\r\nConnection: close\r\n\r\n",
+        health_check.path, upstream.address,
+    );
+    if let Err(err) = stream.get_mut().write_all(request.as_bytes()).await {
+        return Err(format!("could not send the request: {}", err));
+    }
+    let response = match http::read_head(&mut stream).await {
+        Ok(Some(response)) => response,
+        Ok(None) => return Err("connection closed without a response".to_owned()),
+        Err(err) => return Err(err.to_string()),
+    };
+    match response.status() {
+        Ok(status) if status == health_check.expected_status => Ok(()),
+        Ok(status) => Err(format!("unexpected status {}", status)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Whether a server is in rotation, and how many checks in a row have
+/// disagreed with that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Health {
+    healthy: bool,
+    streak: u32,
+}
+
+impl Health {
+    const INITIAL: Health = Health { healthy: true, streak: 0 };
+
+    /// The state after a check that `passed` or not: out of rotation after
+    /// `fall` failures in a row, and back after `rise` passes.
+    fn next(self, passed: bool, health_check: &HealthCheck) -> Health {
+        if passed == self.healthy {
+            return Health { healthy: self.healthy, streak: 0 };
+        }
+        let streak = self.streak + 1;
+        let threshold = if self.healthy { health_check.fall } else { health_check.rise };
+        if streak >= threshold {
+            Health { healthy: passed, streak: 0 }
+        } else {
+            Health { healthy: self.healthy, streak }
+        }
+    }
+}
+
+/// Checks `upstream` every interval for as long as the server runs, taking
+/// it out of rotation after `fall` failures in a row and back after `rise`
+/// passes.
+pub async fn monitor(upstream: Arc<Upstream>, health_check: HealthCheck) {
+    let mut interval = time::interval(health_check.interval());
+    let mut health = Health::INITIAL;
+
+    loop {
+        interval.tick().await;
+        let result = match time::timeout(health_check.timeout(), check(&upstream, &health_check)).await {
+            Ok(result) => result,
+            Err(_) => Err("timed out".to_owned()),
+        };
+
+        let next = health.next(result.is_ok(), &health_check);
+        match result {
+            Ok(_) if next.healthy && !health.healthy => {
+                info!("{} passed its health check; back in rotation", upstream.address);
+            },
+            Err(err) if !next.healthy && health.healthy => {
+                warn!("{} failed its health check ({}); out of rotation", upstream.address, err);
+            },
+            Err(err) => debug!("{} failed its health check: {}", upstream.address, err),
+            Ok(_) => (),
+        }
+        health = next;
+        upstream.set_healthy(health.healthy);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the server is in rotation after each of `results`.
+    fn run(results: &str, rise: u32, fall: u32) -> String {
+        let health_check = HealthCheck { rise, fall, ..HealthCheck::default() };
+        let mut health = Health::INITIAL;
+        results.chars()
+            .map(|result| {
+                health = health.next(result == '+', &health_check);
+                if health.healthy { '+' } else { '-' }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fall_failures_in_a_row_take_a_server_out() {
+        assert_eq!(run("++--", 2, 3), "++++");
+        assert_eq!(run("---", 2, 3), "++-");
+        assert_eq!(run("-", 2, 1), "-");
+    }
+
+    #[test]
+    fn rise_passes_in_a_row_bring_it_back() {
+        assert_eq!(run("---++", 2, 3), "++--+");
+        assert_eq!(run("---+++", 3, 3), "++---+");
+        assert_eq!(run("-+", 1, 1), "-+");
+    }
+
+    #[test]
+    fn mixed_results_reset_the_streak() {
+        assert_eq!(run("--+--+--", 2, 3), "++++++++");
+        assert_eq!(run("----+-++", 2, 3), "++-----+");
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+
+#[derive(Default)]
+struct Counts {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+
+/// Caps the number of client connections handled at once, and keeps track of
+/// how many there are.
+pub struct ConnectionLimit {
+    permits: Option<Arc<Semaphore>>,
+    counts: Arc<Counts>,
+}
+
+/// Held for as long as a connection is being handled.
+pub struct Admission {
+    _permit: Option<OwnedSemaphorePermit>,
+    counts: Arc<Counts>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.counts.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionLimit {
+    /// Creates a limit of `max` connections at once; zero means no limit.
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            permits: match max {
+                0 => None,
+                max => Some(Arc::new(Semaphore::new(max))),
+            },
+            counts: Arc::new(Counts::default()),
+        }
+    }
+
+    fn admit(&self, permit: Option<OwnedSemaphorePermit>) -> Admission {
+        let current = self.counts.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.counts.peak.fetch_max(current, Ordering::Relaxed);
+        Admission {
+            _permit: permit,
+            counts: Arc::clone(&self.counts),
+        }
+    }
+
+    /// Waits until another connection can be handled.
+    pub async fn wait(&self) -> Admission {
+        let permit = match &self.permits {
+            // The semaphore is never closed, so acquiring cannot fail.
+            Some(permits) => Arc::clone(permits).acquire_owned().await.ok(),
+            None => None,
+        };
+        self.admit(permit)
+    }
+
+    /// Admits another connection if the limit has not been reached.
+    pub fn try_admit(&self) -> Option<Admission> {
+        let permit = match &self.permits {
+            Some(permits) => Some(Arc::clone(permits).try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(self.admit(permit))
+    }
+
+    /// The number of connections being handled now.
+    pub fn current(&self) -> usize {
+        self.counts.current.load(Ordering::Relaxed)
+    }
+
+    /// The most connections handled at once since the server started.
+    pub fn peak(&self) -> usize {
+        self.counts.peak.load(Ordering::Relaxed)
+    }
+}
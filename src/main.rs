@@ -1,150 +1,1011 @@
-use std::fs::File;
-use std::io::{self, Read, Write};
-use std::net::{ToSocketAddrs, TcpListener, TcpStream, SocketAddr, IpAddr};
-use std::path::Path;
-use std::thread;
+#[macro_use]
+mod logging;
 
+mod access;
+mod admin;
+mod config;
+mod error;
+mod exchange;
+mod forwarding;
+mod health;
+mod http;
+mod limit;
+mod metrics;
+mod pool;
+mod relay;
+mod rewrite;
+mod routing;
+mod templates;
+mod timeout;
+mod timestamp;
 
-#[derive(Debug)]
-enum ClientError {
-    Utf8Error(std::string::FromUtf8Error),
-    IOError(std::io::Error),
-    ParseIntError(std::num::ParseIntError),
-    NoHostFound,
-    SelfRequested,
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{self, TcpListener, TcpStream};
+use tokio::time;
+
+use access::AccessLog;
+use config::{Config, ConfigError, Redirect, Route, WhenFull};
+use error::ClientError;
+use exchange::Exchange;
+use limit::ConnectionLimit;
+use metrics::Metrics;
+use pool::Pool;
+use routing::{Lease, Router, Upstream};
+use timeout::TimeoutStream;
+
+
+/// How long to wait for an upstream to answer `Expect: 100-continue` before
+/// sending the body anyway, in case it ignores the expectation.
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+/// State shared by every connection.
+struct Server {
+    config: Config,
+    addresses: Vec<SocketAddr>,
+    pool: Pool,
+    connections: ConnectionLimit,
+    access_log: AccessLog,
+    metrics: Metrics,
+    router: Router,
+}
+
+/// A client or upstream connection, with the configured read and write
+/// timeouts applied.
+type Connection = BufReader<TimeoutStream<TcpStream>>;
+
+/// A server a request may be sent to: one of a backend's in reverse-proxy
+/// mode, or the host the request names in forward-proxy mode.
+struct Destination<'a> {
+    upstream: Option<&'a Upstream>,
+    /// The addresses to try. A configured server is only resolved once it is
+    /// reached, so one that is slow to resolve holds up no other.
+    addresses: Addresses,
 }
 
+enum Addresses {
+    Unresolved,
+    Resolved(Vec<SocketAddr>),
+    /// The lookup failed, and is not tried again for the same request.
+    Unknown,
+}
 
-fn read_stream(stream: &mut TcpStream) -> Result<String, ClientError> {
-    let mut buffer = [0; 1024];
-    let mut result = String::new();
+/// An address a request may be sent to, and the configured server it
+/// belongs to in reverse-proxy mode.
+#[derive(Clone, Copy)]
+struct Candidate<'a> {
+    address: SocketAddr,
+    upstream: Option<&'a Upstream>,
+}
 
-    loop {
-        let n = match stream.read(&mut buffer) {
-            Ok(n) => n,
-            Err(err) => return Err(ClientError::IOError(err)),
+impl Candidate<'_> {
+    /// Counts a failed request against the configured server, if any.
+    fn record_failure(&self) {
+        if let Some(upstream) = self.upstream {
+            upstream.record_failure();
+        }
+    }
+}
+
+/// A connection to the upstream a request is being sent to.
+struct UpstreamConnection<'a> {
+    stream: Connection,
+    candidate: Candidate<'a>,
+    /// Whether the whole request body was sent. If the upstream answered
+    /// before that, the rest is still unread and neither connection can be
+    /// used again.
+    request_sent: bool,
+    _lease: Option<Lease<'a>>,
+}
+
+fn get_host(request: &http::Head) -> Result<(String, u16), ClientError> {
+    let mut hosts = request.header_values("Host");
+    let host = hosts.next();
+    if hosts.next().is_some() {
+        return Err(ClientError::InvalidHost("duplicate Host headers"));
+    }
+
+    // An absolute-form target takes precedence over the Host header
+    // (RFC 7230 §5.4).
+    if let Some((authority, _)) = http::split_absolute_form(request.target())? {
+        return split_address(&authority, Some(80));
+    }
+
+    match host {
+        Some(host) => split_address(host, Some(80)),
+        None => Err(ClientError::NoHostFound),
+    }
+}
+
+/// Formats a host and port as an authority, leaving out the default port.
+fn authority(host: &str, port: u16) -> String {
+    let host = match host.contains(':') {
+        true => format!("[{}]", host),
+        false => host.to_owned(),
+    };
+    match port {
+        80 => host,
+        port => format!("{}:{}", host, port),
+    }
+}
+
+fn get_connect_target(request: &http::Head) -> Result<(String, u16), ClientError> {
+    split_address(request.target(), None)
+}
+
+fn is_valid_hostname(host: &str) -> bool {
+    // A single trailing dot marks a fully qualified name.
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Splits an authority (`host`, `host:port`, `[v6]` or `[v6]:port`) into the
+/// host and port, validating both. IPv6 literals are returned unbracketed.
+fn split_address(address: &str, default_port: Option<u16>) -> Result<(String, u16), ClientError> {
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (literal, rest) = match rest.split_once(']') {
+            Some(parts) => parts,
+            None => return Err(ClientError::InvalidHost("unterminated IPv6 literal")),
+        };
+        if literal.parse::<Ipv6Addr>().is_err() {
+            return Err(ClientError::InvalidHost("invalid IPv6 literal"));
+        }
+        let port = match rest {
+            "" => None,
+            rest => match rest.strip_prefix(':') {
+                Some(port) => Some(port),
+                None => return Err(ClientError::InvalidHost("unexpected characters after IPv6 literal")),
+            },
         };
-        
-        if n == 0 { break; }
-        
-        match String::from_utf8(buffer[..n].to_owned()) {
-            Ok(s) => result += &s,
-            Err(err) => return Err(ClientError::Utf8Error(err)),
+        (literal, port)
+    } else {
+        let (host, port) = match address.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
         };
+        if !is_valid_hostname(host) {
+            return Err(ClientError::InvalidHost("invalid host name"));
+        }
+        (host, port)
+    };
 
-        if n < 1024 { break; }
+    let port = match port {
+        // An empty port is allowed and means the default (RFC 3986 §3.2.3).
+        Some("") | None => match default_port {
+            Some(port) => port,
+            None => return Err(ClientError::InvalidHost("missing port")),
+        },
+        Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return Err(ClientError::InvalidHost("port out of range")),
+        },
+        Some(_) => return Err(ClientError::InvalidHost("invalid port")),
     };
+    Ok((host.to_owned(), port))
+}
 
-    return Ok(result);
+/// Resolves `address` to every socket address it names, in the order the
+/// resolver gives them.
+async fn dns_lookup(address: (String, u16)) -> Result<Vec<SocketAddr>, ClientError> {
+    let dns_results: Vec<SocketAddr> = match net::lookup_host(address.clone()).await {
+        Ok(results) => results.collect(),
+        Err(_) => return Err(ClientError::UnknownHost(address.0)),
+    };
+    if dns_results.is_empty() {
+        return Err(ClientError::UnknownHost(address.0));
+    }
+    Ok(dns_results)
 }
 
-fn get_host(request: &String) -> Result<(String, u16), ClientError> {
-    let host = match request.lines().find(|line| line.starts_with("Host")) {
-        Some(host) => match host.trim().split_whitespace().last() {
-            Some(host) => host,
-            None => return Err(ClientError::NoHostFound),
+fn with_timeouts(stream: TcpStream, config: &Config) -> Connection {
+    BufReader::new(TimeoutStream::new(stream, config.timeouts.read(), config.timeouts.write()))
+}
+
+async fn connect(address: SocketAddr, config: &Config) -> Result<TcpStream, ClientError> {
+    let stream = match config.timeouts.connect() {
+        Some(timeout) => match time::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(stream) => stream,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
         },
-        None => return Err(ClientError::NoHostFound),
-    };
-
-    let address_parts: Vec<&str> = host.split(":").collect();
-    if address_parts.len() == 1 {
-        Ok((address_parts[0].to_owned(), 80))
-    } else if address_parts.len() == 2 {
-        Ok((
-            address_parts[0].to_owned(),
-            match address_parts[1].parse::<u16>() {
-                Ok(port) => port,
-                Err(e) => return Err(ClientError::ParseIntError(e))
+        None => TcpStream::connect(address).await,
+    };
+    match stream {
+        Ok(stream) => Ok(stream),
+        Err(err) => Err(ClientError::from_upstream(ClientError::UpstreamConnectFailed(err))),
+    }
+}
+
+/// The addresses of `destination`, resolving it if it has not been yet.
+/// Addresses of this server itself are left out.
+async fn resolve_destination<'d>(destination: &'d mut Destination<'_>, server: &Server) -> Result<&'d [SocketAddr], ClientError> {
+    let name = destination.upstream.map_or("", |upstream| upstream.address.as_str());
+    if let (Addresses::Unresolved, Some(upstream)) = (&destination.addresses, destination.upstream) {
+        let addresses = match split_address(&upstream.address, Some(80)) {
+            Ok(address) => resolve(address, server).await,
+            Err(err) => Err(err),
+        };
+        destination.addresses = match addresses {
+            Ok(addresses) => Addresses::Resolved(addresses.into_iter()
+                .filter(|address| !is_own_address(*address, &server.addresses))
+                .collect()),
+            Err(err) => {
+                warn!("Could not resolve {}: {}", upstream.address, err);
+                Addresses::Unknown
+            },
+        };
+    }
+    match &destination.addresses {
+        Addresses::Resolved(addresses) if !addresses.is_empty() => Ok(addresses),
+        Addresses::Resolved(_) => Err(ClientError::SelfRequested),
+        // A server that cannot be found is the gateway's fault, not the
+        // client's.
+        Addresses::Unresolved | Addresses::Unknown => Err(ClientError::from_upstream(ClientError::UnknownHost(name.to_owned()))),
+    }
+}
+
+/// Connects to the first address of `destinations` that accepts a
+/// connection, resolving each only once those before it have failed.
+async fn connect_any<'a>(destinations: &mut [Destination<'a>], server: &Server) -> Result<(TcpStream, Candidate<'a>), ClientError> {
+    let mut last_error = None;
+    for destination in destinations.iter_mut() {
+        let upstream = destination.upstream;
+        let addresses = match resolve_destination(destination, server).await {
+            Ok(addresses) => addresses,
+            Err(err) => {
+                last_error = Some(err);
+                continue;
+            },
+        };
+        for &address in addresses {
+            let candidate = Candidate { address, upstream };
+            match connect(address, &server.config).await {
+                Ok(stream) => return Ok((stream, candidate)),
+                Err(err) => {
+                    warn!("Could not connect to {}: {}", address, err);
+                    candidate.record_failure();
+                    last_error = Some(err);
+                },
             }
-        ))
-    } else {
-        Err(ClientError::NoHostFound)
+        }
+    }
+    match last_error {
+        Some(err) => Err(err),
+        None => Err(ClientError::from_upstream(ClientError::UpstreamConnectFailed(io::ErrorKind::AddrNotAvailable.into()))),
+    }
+}
+
+/// Whether a request with `method` can safely be sent again (RFC 7231 §4.2.2).
+fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+}
+
+/// Reads the head of the next response from the upstream, allowing it the
+/// first-byte timeout to arrive.
+async fn read_response(upstream: &mut Connection, config: &Config) -> Result<Option<http::Head>, ClientError> {
+    match config.timeouts.first_byte() {
+        Some(timeout) => match time::timeout(timeout, http::read_head(upstream)).await {
+            Ok(result) => result,
+            Err(_) => Err(ClientError::UpstreamTimeout),
+        },
+        None => http::read_head(upstream).await,
     }
 }
 
-fn dns_lookup(address: (String, u16)) -> Result<Option<SocketAddr>, ClientError> {
-    let mut dns_results = match address.to_socket_addrs() {
-        Ok(results) => results,
-        Err(_) => return Err(ClientError::NoHostFound),
+/// Waits for the upstream to answer a request sent with
+/// `Expect: 100-continue` before its body.
+///
+/// Returns a final response if the upstream sends one instead of
+/// `100 Continue`, which then answers the request without its body.
+async fn await_continue(upstream: &mut Connection, config: &Config) -> Result<Option<http::Head>, ClientError> {
+    // Unlike reading a head, waiting for data loses nothing when it times out.
+    match time::timeout(EXPECT_CONTINUE_TIMEOUT, upstream.fill_buf()).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(err)) => return Err(ClientError::IOError(err)),
+        Err(_) => return Ok(None),
     };
-    Ok(dns_results.next())
+    match read_response(upstream, config).await? {
+        // Other interim responses, such as 103, are dropped here.
+        Some(response) if (100..200).contains(&response.status()?) => Ok(None),
+        Some(response) => Ok(Some(response)),
+        None => Err(ClientError::MalformedMessage("upstream closed without responding")),
+    }
 }
 
-fn perform_redirect(mut stream: TcpStream, redirect_address: SocketAddr, request: String) -> Result<(), ClientError> {
-    println!("Forwarding request to {}", redirect_address);
-    let mut redirect_stream = match TcpStream::connect(redirect_address) {
-        Ok(result) => result,
-        Err(err) => return Err(ClientError::IOError(err)),
+/// Sends `request` to the first of `destinations`, reusing a pooled
+/// connection if there is one, and reads the head of the first response.
+/// Destinations that cannot be connected to are skipped over.
+///
+/// A pooled connection may have been closed by the upstream just as it was
+/// taken. Idempotent requests without a body are then retried, on another
+/// pooled connection or finally a new one.
+async fn send_request<'a>(client: &mut Connection, destinations: &mut [Destination<'a>], request: &http::Head, exchange: &mut Exchange, server: &Server) -> Result<(UpstreamConnection<'a>, http::Head), ClientError> {
+    let request_body_length = request.request_body_length()?;
+    let is_retryable = request_body_length == http::BodyLength::Empty && is_idempotent(request.method());
+    let expects_continue = request_body_length != http::BodyLength::Empty
+        && request.version() == "HTTP/1.1"
+        && request.header_values("Expect").any(|value| value.eq_ignore_ascii_case("100-continue"));
+    let request_head = request.to_bytes();
+
+    loop {
+        let pooled = match destinations.first_mut() {
+            Some(destination) => {
+                let upstream = destination.upstream;
+                match resolve_destination(destination, server).await {
+                    Ok(addresses) => Some(Candidate { address: addresses[0], upstream }),
+                    Err(_) => None,
+                }
+            },
+            None => None,
+        };
+        let pooled = pooled.and_then(|candidate| Some((server.pool.take(candidate.address)?, candidate)));
+        let (redirect_stream, candidate, is_reused) = match pooled {
+            Some((stream, candidate)) => (stream, candidate, true),
+            None => {
+                let (stream, candidate) = connect_any(destinations, server).await?;
+                (stream, candidate, false)
+            },
+        };
+        exchange.upstream = Some(candidate.address);
+        exchange.server = candidate.upstream.map(|configured| configured.address.clone());
+        let lease = candidate.upstream.map(Upstream::lease);
+        let mut upstream = with_timeouts(redirect_stream, &server.config);
+
+        match upstream.get_mut().write_all(&request_head).await {
+            Ok(_) => (),
+            Err(_) if is_reused && is_retryable => continue,
+            Err(err) => {
+                candidate.record_failure();
+                return Err(ClientError::from_upstream(ClientError::WriteError(err)));
+            },
+        };
+
+        if expects_continue {
+            match await_continue(&mut upstream, &server.config).await {
+                Ok(Some(response)) => {
+                    let upstream = UpstreamConnection { stream: upstream, candidate, request_sent: false, _lease: lease };
+                    return Ok((upstream, response));
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    candidate.record_failure();
+                    return Err(ClientError::from_upstream(err));
+                },
+            };
+            // The client is waiting for this before it sends the body.
+            match send_response(client, b"HTTP/1.1 100 Continue\r\n\r\n").await {
+                Ok(_) => (),
+                Err(err) => return Err(ClientError::IOError(err)),
+            };
+        }
+
+        client.get_mut().set_read_timeout(server.config.timeouts.body());
+        let sent = http::copy_body(client, upstream.get_mut(), request_body_length).await;
+        client.get_mut().set_read_timeout(server.config.timeouts.read());
+        match sent {
+            Ok(length) => exchange.bytes_in = length,
+            Err(err @ ClientError::WriteError(_)) => {
+                // The upstream may have stopped reading because it has
+                // already answered, such as with 413 for a large upload.
+                if let Ok(Some(response)) = read_response(&mut upstream, &server.config).await {
+                    let upstream = UpstreamConnection { stream: upstream, candidate, request_sent: false, _lease: lease };
+                    return Ok((upstream, response));
+                }
+                return Err(ClientError::from_upstream(err));
+            },
+            Err(err) => return Err(ClientError::from_client(err)),
+        };
+
+        match read_response(&mut upstream, &server.config).await {
+            Ok(Some(response)) => {
+                let upstream = UpstreamConnection { stream: upstream, candidate, request_sent: true, _lease: lease };
+                return Ok((upstream, response));
+            },
+            Ok(None) if is_reused && is_retryable => continue,
+            Err(ClientError::IOError(ref err)) if is_reused && is_retryable && err.kind() == io::ErrorKind::ConnectionReset => continue,
+            Ok(None) => {
+                candidate.record_failure();
+                return Err(ClientError::from_upstream(ClientError::MalformedMessage("upstream closed without responding")));
+            },
+            Err(err) => {
+                candidate.record_failure();
+                return Err(ClientError::from_upstream(err));
+            },
+        };
+    }
+}
+
+/// Forwards `request` to the upstream and relays its response back.
+///
+/// Returns whether the client connection can be used for another request.
+async fn perform_redirect(client: &mut Connection, destinations: &mut [Destination<'_>], route: Option<&Route>, mut request: http::Head, exchange: &mut Exchange, server: &Server) -> Result<bool, ClientError> {
+    let client_keeps_alive = request.keeps_alive();
+    // Where the client sent the request, for rewriting redirects to the
+    // upstream's own address.
+    let public_authority = match route {
+        Some(_) => {
+            let (host, port) = get_host(&request)?;
+            Some(authority(&host, port))
+        },
+        None => None,
     };
-    match redirect_stream.write_all(request.as_bytes()) {
-        Ok(_) => (),
-        Err(err) => return Err(ClientError::IOError(err)),
+
+    // Origin servers expect origin-form, with the authority in Host.
+    if let Some((authority, target)) = http::split_absolute_form(request.target())? {
+        request.set_target(&target);
+        request.set_header("Host", &authority);
+    }
+    if let Some(route) = route {
+        let target = rewrite::rewrite_target(request.target(), &route.rewrite);
+        if target != request.target() {
+            debug!("Rewrote {} to {}", request.target(), target);
+            request.set_target(&target);
+        }
+    }
+
+    // An upgrade is the one hop-by-hop request that is passed on, since the
+    // upgraded connection is relayed end to end.
+    let upgrade = match request.connection_options().iter().any(|option| option == "upgrade") {
+        true => request.header_values("Upgrade").next().map(str::to_owned),
+        false => None,
     };
-    let response = read_stream(&mut redirect_stream);
-    match response {
-        Ok(response) => match stream.write_all(response.as_bytes()) {
-            Ok(_) => Ok(()),
+    let is_upgrade = upgrade.is_some();
+    request.remove_hop_by_hop_headers();
+    match upgrade {
+        Some(protocols) => {
+            request.set_header("Upgrade", &protocols);
+            request.set_header("Connection", "upgrade");
+        },
+        // Ask the upstream to keep the connection open so it can be pooled.
+        None => request.set_header("Connection", "keep-alive"),
+    }
+    forwarding::add_request_headers(&mut request, exchange.client, &server.config.forwarding);
+    rewrite::apply_header_rules(&mut request, &server.config.headers.request);
+    if let Some(route) = route {
+        rewrite::apply_header_rules(&mut request, &route.headers.request);
+    }
+
+    let (mut upstream, mut response) = send_request(client, destinations, &request, exchange, server).await?;
+    debug!("Forwarded request to {}", upstream.candidate.address);
+
+    loop {
+        let (status, response_body_length) = match (response.status(), response.response_body_length(request.method())) {
+            (Ok(status), Ok(response_body_length)) => (status, response_body_length),
+            (Err(err), _) | (_, Err(err)) => return Err(ClientError::from_upstream(err)),
+        };
+        let is_final = !(100..200).contains(&status);
+        let upstream_keeps_alive = !is_upgrade
+            && upstream.request_sent
+            && response.keeps_alive()
+            && response_body_length != http::BodyLength::UntilClose;
+
+        // A body delimited by closing the connection, or a request body left
+        // unread, leaves no way to carry on afterwards.
+        let keep_alive = client_keeps_alive
+            && upstream.request_sent
+            && response_body_length != http::BodyLength::UntilClose;
+        let upgrade = response.header_values("Upgrade").next().map(str::to_owned);
+        response.remove_overridden_content_length();
+        response.remove_hop_by_hop_headers();
+        if status == 101 {
+            if let Some(protocol) = upgrade {
+                response.set_header("Upgrade", &protocol);
+            }
+            response.set_header("Connection", "upgrade");
+        } else if is_final {
+            response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+        }
+        forwarding::add_via(&mut response, &server.config.forwarding);
+        if let (Some(route), Some(public_authority)) = (route, &public_authority) {
+            let mut authorities = vec![upstream.candidate.address.to_string()];
+            authorities.extend(upstream.candidate.upstream.map(|configured| configured.address.clone()));
+            let location = response.header_values("Location").next()
+                .and_then(|location| rewrite::rewrite_location(location, &authorities, public_authority, &route.rewrite));
+            if let Some(location) = location {
+                response.set_header("Location", &location);
+            }
+        }
+        rewrite::apply_header_rules(&mut response, &server.config.headers.response);
+        if let Some(route) = route {
+            rewrite::apply_header_rules(&mut response, &route.headers.response);
+        }
+        // We speak HTTP/1.1 to the client whatever the upstream speaks
+        // (RFC 7230 §2.6).
+        response.set_response_version("HTTP/1.1");
+        if is_final || status == 101 {
+            exchange.status = Some(status);
+            match upstream.candidate.upstream {
+                Some(_) if status >= 500 => upstream.candidate.record_failure(),
+                Some(configured) => configured.record_success(),
+                None => (),
+            }
+        }
+
+        match send_response(client, &response.to_bytes()).await {
+            Ok(_) => (),
+            Err(err) => return Err(ClientError::IOError(err)),
+        };
+
+        if status == 101 {
+            // Either side of the upgraded connection may stay quiet while the
+            // other sends, so reads are left untimed.
+            client.get_mut().set_read_timeout(None);
+            upstream.stream.get_mut().set_read_timeout(None);
+            return match relay::splice(client, &mut upstream.stream).await {
+                Ok((sent, received)) => {
+                    exchange.bytes_in += sent;
+                    exchange.bytes_out += received;
+                    Ok(false)
+                },
+                Err(err) => Err(ClientError::ResponseInterrupted(Box::new(err))),
+            };
+        }
+        match http::copy_body(&mut upstream.stream, client.get_mut(), response_body_length).await {
+            Ok(length) => exchange.bytes_out += length,
+            Err(err) => return Err(ClientError::ResponseInterrupted(Box::new(err))),
+        };
+        if is_final {
+            // Only a connection with nothing left unread can be reused.
+            if upstream_keeps_alive && upstream.stream.buffer().is_empty() {
+                server.pool.put(upstream.candidate.address, upstream.stream.into_inner().into_inner());
+            }
+            return Ok(keep_alive);
+        }
+
+        // Interim responses are followed by the final one.
+        response = match read_response(&mut upstream.stream, &server.config).await {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                upstream.candidate.record_failure();
+                return Err(ClientError::from_upstream(ClientError::MalformedMessage("upstream closed without responding")));
+            },
             Err(err) => {
-                Err(ClientError::IOError(err))
+                upstream.candidate.record_failure();
+                return Err(ClientError::from_upstream(err));
             },
+        };
+    }
+}
+
+async fn perform_tunnel(client: &mut Connection, destinations: &mut [Destination<'_>], exchange: &mut Exchange, server: &Server) -> Result<bool, ClientError> {
+    let config = &server.config;
+    let (tunnel_stream, candidate) = connect_any(destinations, server).await?;
+    debug!("Opened tunnel to {}", candidate.address);
+    exchange.upstream = Some(candidate.address);
+    match send_response(client, b"HTTP/1.1 200 Connection Established\r\n\r\n").await {
+        Ok(_) => (),
+        Err(err) => return Err(ClientError::IOError(err)),
+    };
+    exchange.status = Some(200);
+    // Either side of the tunnel may stay quiet while the other sends, so
+    // reads are left untimed.
+    let mut tunnel = with_timeouts(tunnel_stream, config);
+    client.get_mut().set_read_timeout(None);
+    tunnel.get_mut().set_read_timeout(None);
+    match relay::splice(client, &mut tunnel).await {
+        Ok((sent, received)) => {
+            exchange.bytes_in = sent;
+            exchange.bytes_out = received;
+            Ok(false)
         },
-        Err(err) => {
-            Err(err)
+        Err(err) => Err(ClientError::ResponseInterrupted(Box::new(err))),
+    }
+}
+
+async fn send_response(stream: &mut Connection, response: &[u8]) -> io::Result<()> {
+    stream.write_all(response).await
+}
+
+/// Answers the client with the error response for `error`, then reports it.
+async fn send_error(stream: &mut Connection, status: u16, error: ClientError, exchange: &mut Exchange, config: &Config) -> Result<(), ClientError> {
+    let response = templates::render_error(&config.responses, status, exchange).await;
+    let head_length = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(position) => position + 4,
+        None => response.len(),
+    };
+    exchange.status = Some(status);
+    exchange.bytes_out = (response.len() - head_length) as u64;
+    match send_response(stream, &response).await {
+        Ok(_) => Err(error),
+        Err(e) => Err(ClientError::IOError(e))
+    }
+}
+
+/// Whether `address` is one of the addresses this server is listening on.
+///
+/// Listeners bound to an unspecified address (`0.0.0.0` or `::`) accept
+/// connections on every local interface, so for those any local IP with a
+/// matching port counts.
+fn is_own_address(address: SocketAddr, server_addresses: &[SocketAddr]) -> bool {
+    let ip = address.ip().to_canonical();
+    server_addresses.iter().any(|server_address| {
+        if server_address.port() != address.port() {
+            return false;
+        }
+        let server_ip = server_address.ip().to_canonical();
+        if server_ip == ip {
+            return true;
+        }
+        // Only local addresses can be bound to.
+        server_ip.is_unspecified()
+            && (ip.is_loopback() || ip.is_unspecified() || UdpSocket::bind((ip, 0)).is_ok())
+    })
+}
+
+/// Reads the head of the next request, allowing the client the header
+/// timeout to send all of it.
+async fn read_request(client: &mut Connection, config: &Config) -> Result<Option<http::Head>, ClientError> {
+    let result = match config.timeouts.header() {
+        Some(timeout) => match time::timeout(timeout, http::read_head(client)).await {
+            Ok(result) => result,
+            Err(_) => Err(ClientError::RequestTimeout),
         },
+        None => http::read_head(client).await,
+    };
+    if let Ok(Some(request)) = &result {
+        request.check_request_line().map_err(ClientError::from_client)?;
     }
+    result.map_err(ClientError::from_client)
 }
 
-fn send_response(stream: &mut TcpStream, response: String) -> std::io::Result<()> {
-    stream.write_all(response.as_bytes())
+async fn resolve(address: (String, u16), server: &Server) -> Result<Vec<SocketAddr>, ClientError> {
+    let lookup_started = Instant::now();
+    let result = dns_lookup(address).await;
+    server.metrics.record_dns_lookup(lookup_started.elapsed());
+    result
 }
 
-fn send_response_file(stream: &mut TcpStream, response_name: &str) -> std::io::Result<()> {
-    let file_path = Path::new("responses").join(response_name.to_owned() + ".http");
-    let mut file = File::open(file_path)?;
-    
-    let mut file_contents = String::new();
-    file.read_to_string(&mut file_contents)?;
+/// Picks the route for `request` from the routing table, and the servers to
+/// try in order of preference, of which there are none if the route
+/// redirects. Only configured backends are ever resolved, never names the
+/// client supplies.
+fn route_request<'a>(request: &http::Head, exchange: &mut Exchange, server: &'a Server) -> Result<(&'a Route, Vec<Destination<'a>>), ClientError> {
+    if request.method() == "CONNECT" {
+        return Err(ClientError::NoRoute(request.target().to_owned()));
+    }
+    let (host, _) = get_host(request)?;
+    exchange.host = Some(host.clone());
+    let path = match http::split_absolute_form(request.target())? {
+        Some((_, path)) => path,
+        None => request.target().to_owned(),
+    };
 
-    send_response(stream, file_contents)
+    let route = match server.router.route(&host, &path) {
+        Some(route) => route,
+        None => return Err(ClientError::NoRoute(host)),
+    };
+    let backend = match server.router.backend(route) {
+        Some(backend) => backend,
+        None => return Ok((route, Vec::new())),
+    };
+    let key = match backend.hash_header.as_deref().and_then(|name| request.header_values(name).next()) {
+        Some(value) => value.to_owned(),
+        None => exchange.client.ip().to_string(),
+    };
+    let destinations = backend.candidates(&key).into_iter()
+        .map(|upstream| Destination { upstream: Some(upstream), addresses: Addresses::Unresolved })
+        .collect();
+    Ok((route, destinations))
 }
 
-fn handle_client(mut stream: TcpStream, server_address: SocketAddr) -> Result<(), ClientError> {
-    let request_text = read_stream(&mut stream)?;
-    let address = get_host(&request_text)?;
-    let redirect_address = dns_lookup(address)?;
+/// Answers `request` with the redirect its route is configured with.
+///
+/// Returns whether the client connection can be used for another request.
+async fn send_redirect(client: &mut Connection, request: &http::Head, route: &Route, redirect: &Redirect, exchange: &mut Exchange, server: &Server) -> Result<bool, ClientError> {
+    let (host, port) = get_host(request)?;
+    let scheme = redirect.scheme.as_deref().unwrap_or(forwarding::PROTOCOL);
+    let location_authority = match &redirect.host {
+        Some(host) => host.clone(),
+        // The port only means the same thing under the same scheme.
+        None if scheme.eq_ignore_ascii_case(forwarding::PROTOCOL) => authority(&host, port),
+        None => authority(&host, 80),
+    };
+    let target = match http::split_absolute_form(request.target())? {
+        Some((_, target)) => target,
+        None => request.target().to_owned(),
+    };
+    let location = format!("{}://{}{}", scheme, location_authority, rewrite::rewrite_target(&target, &route.rewrite));
+    // The request body is not read, so nothing can follow it.
+    let keep_alive = request.keeps_alive() && request.request_body_length()? == http::BodyLength::Empty;
 
-    match redirect_address {
-        Some(redirect_address) =>
-            if redirect_address == server_address {
-                return match send_response_file(&mut stream, "error508") {
-                    Ok(_) => Err(ClientError::SelfRequested),
-                    Err(e) => Err(ClientError::IOError(e))
-                }
-            } else {
-                perform_redirect(stream, redirect_address, request_text)
-            },
-        None => return Err(ClientError::NoHostFound),
+    let mut response = http::Head {
+        start_line: format!("HTTP/1.1 {} {}", redirect.status, http::reason_phrase(redirect.status)),
+        headers: Vec::new(),
+    };
+    response.set_header("Location", &location);
+    response.set_header("Content-Length", "0");
+    response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    rewrite::apply_header_rules(&mut response, &server.config.headers.response);
+    rewrite::apply_header_rules(&mut response, &route.headers.response);
+    debug!("Redirecting to {}", location);
+
+    exchange.status = Some(redirect.status);
+    match send_response(client, &response.to_bytes()).await {
+        Ok(_) => Ok(keep_alive),
+        Err(err) => Err(ClientError::IOError(err)),
     }
 }
 
-fn main() -> io::Result<()> {
-    let ip_address = match "127.0.0.1".parse::<IpAddr>() {
-        Ok(addr) => addr,
-        Err(e) => panic!("An error occurred: {}", e),
+/// Reads and handles one request from the client.
+///
+/// Returns whether the connection can be used for another request.
+async fn process_request(client: &mut Connection, exchange: &mut Exchange, server: &Server) -> Result<bool, ClientError> {
+    let request = match read_request(client, &server.config).await? {
+        Some(request) => request,
+        None => return Ok(false),
     };
-    let server_address = SocketAddr::new(ip_address, 8080);
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
+    exchange.set_request(&request);
+    let is_tunnel = request.method() == "CONNECT";
+    let (route, mut destinations) = if server.router.is_enabled() {
+        let (route, destinations) = route_request(&request, exchange, server)?;
+        (Some(route), destinations)
+    } else {
+        let address = if is_tunnel {
+            get_connect_target(&request)?
+        } else {
+            get_host(&request)?
+        };
+        exchange.host = Some(address.0.clone());
+        let addresses: Vec<SocketAddr> = resolve(address, server).await?.into_iter()
+            .filter(|address| !is_own_address(*address, &server.addresses))
+            .collect();
+        if addresses.is_empty() {
+            return Err(ClientError::SelfRequested);
+        }
+        (None, vec![Destination { upstream: None, addresses: Addresses::Resolved(addresses) }])
+    };
+
+    if let Some(route) = route {
+        if let Some(redirect) = &route.redirect {
+            return send_redirect(client, &request, route, redirect, exchange, server).await;
+        }
+    }
 
-    for stream in listener.incoming() {
-        let stream = stream?;
-        thread::spawn(move || {
-            match handle_client(stream, server_address) {
+    if is_tunnel {
+        perform_tunnel(client, &mut destinations, exchange, server).await
+    } else {
+        perform_redirect(client, &mut destinations, route, request, exchange, server).await
+    }
+}
+
+/// Waits up to the idle timeout for the client to start another request on
+/// a persistent connection. Returns `false` if it closed or stayed idle.
+async fn await_request(client: &mut Connection, config: &Config) -> Result<bool, ClientError> {
+    client.get_mut().set_read_timeout(config.timeouts.idle());
+    let has_data = client.fill_buf().await.map(|buffer| !buffer.is_empty());
+    client.get_mut().set_read_timeout(config.timeouts.read());
+
+    match has_data {
+        Ok(has_data) => Ok(has_data),
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(false),
+        Err(err) => Err(ClientError::IOError(err)),
+    }
+}
+
+async fn handle_client(stream: TcpStream, peer: SocketAddr, server: &Server) -> Result<(), ClientError> {
+    let config = &server.config;
+    let mut client = with_timeouts(stream, config);
+
+    let mut is_first_request = true;
+    loop {
+        if !is_first_request && !await_request(&mut client, config).await? {
+            return Ok(());
+        }
+        is_first_request = false;
+
+        let mut exchange = Exchange::start(peer);
+        let result = process_request(&mut client, &mut exchange, server).await;
+        if let Err(err) = &result {
+            server.metrics.record_error(err);
+        }
+        let result = match result {
+            Err(err) => match err.status() {
+                Some(status) => send_error(&mut client, status, err, &mut exchange, config).await.map(|_| false),
+                None => Err(err),
+            },
+            result => result,
+        };
+        // A connection closed before a request began has nothing to report.
+        if exchange.request.is_some() || exchange.status.is_some() {
+            server.access_log.record(&exchange);
+            server.metrics.record_exchange(&exchange);
+        }
+
+        if !result? {
+            return Ok(());
+        }
+    }
+}
+
+/// Answers a connection over the limit with `503 Service Unavailable`.
+async fn reject_client(stream: TcpStream, peer: SocketAddr, server: &Server) -> Result<(), ClientError> {
+    let mut client = with_timeouts(stream, &server.config);
+    let mut exchange = Exchange::start(peer);
+    server.metrics.record_error(&ClientError::Overloaded);
+    let result = send_error(&mut client, 503, ClientError::Overloaded, &mut exchange, &server.config).await;
+    server.access_log.record(&exchange);
+    server.metrics.record_exchange(&exchange);
+    result
+}
+
+async fn serve(listener: TcpListener, server: Arc<Server>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let admission = match server.config.connections.when_full {
+            // Further connections wait in the listen backlog meanwhile.
+            WhenFull::Queue => Some(server.connections.wait().await),
+            WhenFull::Reject => server.connections.try_admit(),
+        };
+        debug!("{} connections in flight, peak {}", server.connections.current(), server.connections.peak());
+
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let result = match admission {
+                Some(_admission) => handle_client(stream, peer, &server).await,
+                None => reject_client(stream, peer, &server).await,
+            };
+            match result {
                 Ok(_) => (),
-                Err(e) => eprintln!("An error occurred: {:?}", e)
+                Err(e) => error!("An error occurred: {}", e)
             };
         });
     }
+}
+
+/// Reopens the access log whenever the process receives `SIGHUP`, as log
+/// rotation tools expect.
+#[cfg(unix)]
+async fn reopen_on_hangup(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => return error!("Could not listen for SIGHUP: {}", e),
+    };
+    while hangups.recv().await.is_some() {
+        match server.access_log.reopen() {
+            Ok(_) => info!("Reopened the access log"),
+            Err(e) => error!("Could not reopen the access log: {}", e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reopen_on_hangup(_server: Arc<Server>) {}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let config = match Config::from_args(std::env::args()) {
+        Ok(config) => config,
+        Err(ConfigError::HelpRequested) => {
+            println!("{}", ConfigError::HelpRequested);
+            return Ok(());
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        },
+    };
+    logging::set_level(config.log_level);
+
+    let mut listeners = Vec::new();
+    for address in config.listen_addresses() {
+        let listener = TcpListener::bind(address).await?;
+        info!("Listening on {}", listener.local_addr()?);
+        listeners.push(listener);
+    }
+    let addresses = listeners.iter()
+        .map(|listener| listener.local_addr())
+        .collect::<io::Result<Vec<_>>>()?;
+    let admin_listener = match config.admin.bind {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            info!("Serving metrics on {}", listener.local_addr()?);
+            Some(listener)
+        },
+        None => None,
+    };
+
+    let pool = Pool::new(config.pool.max_idle_per_host, config.pool.idle_timeout());
+    let connections = ConnectionLimit::new(config.connections.max);
+    let access_log = match AccessLog::new(&config.access_log) {
+        Ok(access_log) => access_log,
+        Err(e) => {
+            eprintln!("could not open the access log: {}", e);
+            process::exit(2);
+        },
+    };
+    let metrics = Metrics::default();
+    let router = Router::new(&config);
+    let server = Arc::new(Server { config, addresses, pool, connections, access_log, metrics, router });
+    tokio::spawn(reopen_on_hangup(Arc::clone(&server)));
+    for (_, backend) in server.router.backends() {
+        if let Some(health_check) = &backend.health_check {
+            for upstream in backend.servers() {
+                tokio::spawn(health::monitor(Arc::clone(upstream), health_check.clone()));
+            }
+        }
+    }
+    if let Some(listener) = admin_listener {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, server).await {
+                error!("The admin listener failed: {}", e);
+            }
+        });
+    }
+
+    let accept_tasks = listeners.into_iter()
+        .map(|listener| serve(listener, Arc::clone(&server)));
+    future::try_join_all(accept_tasks).await?;
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_split_into_host_and_port() {
+        let split = |address| split_address(address, Some(80)).unwrap();
+        assert_eq!(split("example.com"), ("example.com".to_owned(), 80));
+        assert_eq!(split("example.com:8080"), ("example.com".to_owned(), 8080));
+        assert_eq!(split("example.com:"), ("example.com".to_owned(), 80));
+        assert_eq!(split("127.0.0.1:81"), ("127.0.0.1".to_owned(), 81));
+        assert_eq!(split("[::1]"), ("::1".to_owned(), 80));
+        assert_eq!(split("[::1]:8080"), ("::1".to_owned(), 8080));
+        assert_eq!(split("[::1]:"), ("::1".to_owned(), 80));
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        for address in ["", ":80", "example.com:http", "example.com:+80", "example.com:65536", "exa mple.com", "[::1", "[::g]", "[::1]80", "::1", "a..b"] {
+            assert!(split_address(address, Some(80)).is_err(), "accepted `{}`", address);
+        }
+    }
+
+    #[test]
+    fn connect_targets_need_a_port() {
+        assert!(split_address("example.com", None).is_err());
+        assert!(split_address("[::1]", None).is_err());
+        assert_eq!(split_address("example.com:443", None).unwrap(), ("example.com".to_owned(), 443));
+    }
+
+    #[test]
+    fn hostnames_are_validated() {
+        assert!(is_valid_hostname("example.com"));
+        assert!(is_valid_hostname("example.com."));
+        assert!(is_valid_hostname("my_host-1"));
+        assert!(!is_valid_hostname("."));
+        assert!(!is_valid_hostname("example..com"));
+        assert!(!is_valid_hostname("exa/mple.com"));
+        assert!(!is_valid_hostname(&"a".repeat(64)));
+        assert!(!is_valid_hostname(&["a"; 128].join(".")));
+    }
+
+    #[test]
+    fn host_header_and_absolute_form() {
+        let request = http::parse_head("GET / HTTP/1.1\r\nhost: Example.com:8080\r\n\r\n").unwrap();
+        assert_eq!(get_host(&request).unwrap(), ("Example.com".to_owned(), 8080));
+        let request = http::parse_head("GET http://a.example/ HTTP/1.1\r\nHost: b.example\r\n\r\n").unwrap();
+        assert_eq!(get_host(&request).unwrap(), ("a.example".to_owned(), 80));
+        let request = http::parse_head("GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n").unwrap();
+        assert!(get_host(&request).is_err());
+        let request = http::parse_head("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(get_host(&request), Err(ClientError::NoHostFound)));
+    }
+}
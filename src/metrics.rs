@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::error::ClientError;
+use crate::exchange::Exchange;
+use crate::limit::ConnectionLimit;
+
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct HistogramData {
+    /// Observations per bucket, not cumulative; the last is for values
+    /// above every bound.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Histogram(Mutex<HistogramData>);
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(BUCKETS.len());
+        let mut data = lock(&self.0);
+        data.counts[bucket] += 1;
+        data.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let data = lock(&self.0);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(data.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += data.counts[BUCKETS.len()];
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, data.sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+/// Counters and histograms describing the traffic handled, for the
+/// `/metrics` endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    /// Completed requests by status and upstream: a configured server, or
+    /// `forward` for any host in forward-proxy mode, which clients choose.
+    requests: Mutex<BTreeMap<(u16, String), u64>>,
+    request_duration: Histogram,
+    dns_lookup_duration: Histogram,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    /// Counts a request once it has been answered.
+    pub fn record_exchange(&self, exchange: &Exchange) {
+        let status = match exchange.status {
+            Some(status) => status,
+            None => return,
+        };
+        let upstream = match (&exchange.server, exchange.upstream) {
+            (Some(server), _) => server.clone(),
+            (None, Some(_)) => "forward".to_owned(),
+            (None, None) => String::new(),
+        };
+        *lock(&self.requests).entry((status, upstream)).or_default() += 1;
+        self.request_duration.observe(exchange.started.elapsed());
+        self.bytes_received.fetch_add(exchange.bytes_in, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(exchange.bytes_out, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, error: &ClientError) {
+        *lock(&self.errors).entry(error.kind()).or_default() += 1;
+    }
+
+    pub fn record_dns_lookup(&self, duration: Duration) {
+        self.dns_lookup_duration.observe(duration);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self, connections: &ConnectionLimit) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP router_requests_total Requests answered, by status and upstream.\n");
+        out.push_str("# TYPE router_requests_total counter\n");
+        for ((status, upstream), count) in lock(&self.requests).iter() {
+            let _ = writeln!(out, "router_requests_total{{status=\"{}\",upstream=\"{}\"}} {}", status, escape_label(upstream), count);
+        }
+        self.request_duration.render(&mut out, "router_request_duration_seconds", "Time from the start of a request until it was answered.");
+        self.dns_lookup_duration.render(&mut out, "router_dns_lookup_duration_seconds", "Time taken to resolve upstream host names.");
+
+        out.push_str("# HELP router_connections_active Client connections being handled.\n");
+        out.push_str("# TYPE router_connections_active gauge\n");
+        let _ = writeln!(out, "router_connections_active {}", connections.current());
+        out.push_str("# HELP router_connections_peak Most client connections handled at once.\n");
+        out.push_str("# TYPE router_connections_peak gauge\n");
+        let _ = writeln!(out, "router_connections_peak {}", connections.peak());
+
+        out.push_str("# HELP router_received_bytes_total Body bytes received from clients.\n");
+        out.push_str("# TYPE router_received_bytes_total counter\n");
+        let _ = writeln!(out, "router_received_bytes_total {}", self.bytes_received.load(Ordering::Relaxed));
+        out.push_str("# HELP router_sent_bytes_total Body bytes sent to clients.\n");
+        out.push_str("# TYPE router_sent_bytes_total counter\n");
+        let _ = writeln!(out, "router_sent_bytes_total {}", self.bytes_sent.load(Ordering::Relaxed));
+
+        out.push_str("# HELP router_errors_total Failed requests, by kind of error.\n");
+        out.push_str("# TYPE router_errors_total counter\n");
+        for (kind, count) in lock(&self.errors).iter() {
+            let _ = writeln!(out, "router_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        out
+    }
+}
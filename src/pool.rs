@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use tokio::net::TcpStream;
+
+
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+
+/// Idle keep-alive connections to upstreams, kept for reuse by later
+/// requests to the same address.
+pub struct Pool {
+    idle: Mutex<HashMap<SocketAddr, Vec<IdleConnection>>>,
+    max_idle_per_host: usize,
+    idle_timeout: Option<Duration>,
+}
+
+/// Whether the peer has closed `stream`, or sent something unsolicited,
+/// while it sat idle. Either way it is no use for a new request.
+fn is_stale(stream: &TcpStream) -> bool {
+    let mut byte = [0; 1];
+    // A live, quiet connection has nothing to read yet.
+    stream.peek(&mut byte).now_or_never().is_some()
+}
+
+impl Pool {
+    /// Creates a pool holding at most `max_idle_per_host` connections per
+    /// upstream, each for at most `idle_timeout`. A limit of zero disables
+    /// pooling.
+    pub fn new(max_idle_per_host: usize, idle_timeout: Option<Duration>) -> Pool {
+        Pool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_host,
+            idle_timeout,
+        }
+    }
+
+    fn is_expired(&self, connection: &IdleConnection, now: Instant) -> bool {
+        match self.idle_timeout {
+            Some(timeout) => now.duration_since(connection.since) >= timeout,
+            None => false,
+        }
+    }
+
+    /// Takes the most recently used live connection to `address`, if any.
+    pub fn take(&self, address: SocketAddr) -> Option<TcpStream> {
+        let mut idle = match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let connections = idle.get_mut(&address)?;
+        let now = Instant::now();
+
+        while let Some(connection) = connections.pop() {
+            if !self.is_expired(&connection, now) && !is_stale(&connection.stream) {
+                return Some(connection.stream);
+            }
+        }
+        idle.remove(&address);
+        None
+    }
+
+    /// Returns a connection to the pool once its last response has been
+    /// read in full. Expired connections to every upstream are closed here
+    /// too.
+    pub fn put(&self, address: SocketAddr, stream: TcpStream) {
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+        let mut idle = match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+
+        idle.retain(|_, connections| {
+            connections.retain(|connection| !self.is_expired(connection, now));
+            !connections.is_empty()
+        });
+
+        let connections = idle.entry(address).or_default();
+        if connections.len() >= self.max_idle_per_host {
+            // Drop the one that has been idle longest.
+            connections.remove(0);
+        }
+        connections.push(IdleConnection { stream, since: now });
+    }
+}
@@ -0,0 +1,20 @@
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+use crate::error::ClientError;
+
+
+/// Copies bytes in both directions between `client` and `upstream` until both
+/// sides have finished sending. Anything already buffered in either reader is
+/// forwarded first, and the end of each stream is passed on to the other side.
+///
+/// Returns the number of bytes sent to the upstream and to the client.
+pub async fn splice<C, U>(client: &mut C, upstream: &mut U) -> Result<(u64, u64), ClientError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    match io::copy_bidirectional(client, upstream).await {
+        Ok((sent, received)) => Ok((sent, received)),
+        Err(err) => Err(ClientError::IOError(err)),
+    }
+}
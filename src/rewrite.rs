@@ -0,0 +1,234 @@
+use crate::config::{HeaderAction, HeaderRule, PathRewrite};
+use crate::forwarding;
+use crate::http::Head;
+
+
+/// Applies header rules to a request or response head, in order.
+pub fn apply_header_rules(head: &mut Head, rules: &[HeaderRule]) {
+    for rule in rules {
+        // Missing values and patterns are rejected when loading the
+        // configuration.
+        let value = rule.value.as_deref().unwrap_or("");
+        match rule.action {
+            HeaderAction::Add => head.headers.push((rule.name.clone(), value.to_owned())),
+            HeaderAction::Set => head.set_header(&rule.name, value),
+            HeaderAction::Remove => head.remove_header(&rule.name),
+            HeaderAction::Replace => {
+                let pattern = match &rule.pattern {
+                    Some(pattern) => pattern,
+                    None => continue,
+                };
+                for (field, field_value) in head.headers.iter_mut() {
+                    if field.eq_ignore_ascii_case(&rule.name) {
+                        let replaced = pattern.replace_all(field_value, value).into_owned();
+                        *field_value = replaced;
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// `path` without `prefix`, if it starts with it on a segment boundary.
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some("") => Some("/"),
+        Some(rest) if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// Rewrites the path of an origin-form request target, keeping its query.
+pub fn rewrite_target(target: &str, rewrite: &PathRewrite) -> String {
+    // `OPTIONS *` has no path to rewrite.
+    if !target.starts_with('/') {
+        return target.to_owned();
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    let mut path = path.to_owned();
+    if let Some(prefix) = &rewrite.strip_prefix {
+        // Only whole segments are stripped, so `/api` leaves `/apis` alone.
+        if let Some(rest) = strip_path_prefix(&path, prefix) {
+            path = rest.to_owned();
+        }
+    }
+    if let Some(prefix) = &rewrite.add_prefix {
+        path = format!("{}{}", prefix.trim_end_matches('/'), path);
+    }
+    if let (Some(pattern), Some(replacement)) = (&rewrite.pattern, &rewrite.replacement) {
+        path = pattern.replace_all(&path, replacement.as_str()).into_owned();
+    }
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+
+    match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+/// Points a `Location` that names one of the upstream's own `authorities`
+/// at `public_authority`, the host the client asked for, as the client
+/// cannot reach the upstream directly. A prefix the route added is taken
+/// off and one it stripped put back, unless the path lies outside the added
+/// prefix; pattern replacements cannot be undone.
+///
+/// Returns `None` if the location names some other host.
+pub fn rewrite_location(location: &str, authorities: &[String], public_authority: &str, rewrite: &PathRewrite) -> Option<String> {
+    // Upstreams are only ever reached over the same protocol as this proxy.
+    let prefix = format!("{}://", forwarding::PROTOCOL);
+    let rest = match location.get(..prefix.len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(&prefix) => &location[prefix.len()..],
+        _ => return None,
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, target) = rest.split_at(authority_end);
+
+    // Port 80 is the same whether or not it is written.
+    let normalize = |authority: &str| {
+        let authority = authority.to_ascii_lowercase();
+        match authority.strip_suffix(":80") {
+            Some(authority) => authority.to_owned(),
+            None => authority,
+        }
+    };
+    if !authorities.iter().any(|upstream| normalize(upstream) == normalize(authority)) {
+        return None;
+    }
+
+    let path_end = target.find(['?', '#']).unwrap_or(target.len());
+    let (path, suffix) = match &target[..path_end] {
+        "" => ("/", &target[path_end..]),
+        path => (path, &target[path_end..]),
+    };
+    let mut path = path.to_owned();
+    if let Some(added) = &rewrite.add_prefix {
+        match strip_path_prefix(&path, added) {
+            Some(rest) => path = rest.to_owned(),
+            // No public path leads outside the added prefix, so the path is
+            // left as the upstream gave it.
+            None => return Some(format!("{}{}{}{}", prefix, public_authority, path, suffix)),
+        }
+    }
+    if let Some(prefix) = &rewrite.strip_prefix {
+        path = format!("{}{}", prefix.trim_end_matches('/'), path);
+    }
+    Some(format!("{}{}{}{}", prefix, public_authority, path, suffix))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http;
+
+    fn rules(text: &str) -> Vec<HeaderRule> {
+        #[derive(serde::Deserialize)]
+        struct Rules {
+            rules: Vec<HeaderRule>,
+        }
+        toml::from_str::<Rules>(text).unwrap().rules
+    }
+
+    fn path_rewrite(text: &str) -> PathRewrite {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn prefixes_are_stripped_on_segment_boundaries() {
+        let rewrite = path_rewrite("strip_prefix = \"/billing\"");
+        assert_eq!(rewrite_target("/billing", &rewrite), "/");
+        assert_eq!(rewrite_target("/billing/", &rewrite), "/");
+        assert_eq!(rewrite_target("/billing/invoices/7", &rewrite), "/invoices/7");
+        assert_eq!(rewrite_target("/billingx", &rewrite), "/billingx");
+        assert_eq!(rewrite_target("/other/billing", &rewrite), "/other/billing");
+
+        let rewrite = path_rewrite("strip_prefix = \"/billing/\"");
+        assert_eq!(rewrite_target("/billing/invoices", &rewrite), "/invoices");
+        assert_eq!(rewrite_target("/billing", &rewrite), "/");
+    }
+
+    #[test]
+    fn prefixes_are_added_and_patterns_replaced() {
+        let rewrite = path_rewrite("add_prefix = \"/v2/\"");
+        assert_eq!(rewrite_target("/users", &rewrite), "/v2/users");
+
+        let rewrite = path_rewrite(r#"
+            strip_prefix = "/api"
+            add_prefix = "/v2"
+            pattern = "^/v2/users/(\\d+)$"
+            replacement = "/v2/people/$1"
+        "#);
+        assert_eq!(rewrite_target("/api/users/42", &rewrite), "/v2/people/42");
+        assert_eq!(rewrite_target("/api/users/me", &rewrite), "/v2/users/me");
+
+        let rewrite = path_rewrite("pattern = \"^/.*$\"\nreplacement = \"index\"");
+        assert_eq!(rewrite_target("/anything", &rewrite), "/index");
+    }
+
+    #[test]
+    fn query_strings_are_kept() {
+        let rewrite = path_rewrite("strip_prefix = \"/billing\"\npattern = \"a\"\nreplacement = \"b\"");
+        assert_eq!(rewrite_target("/billing/a?a=1&b=/billing", &rewrite), "/b?a=1&b=/billing");
+        assert_eq!(rewrite_target("/billing?", &rewrite), "/?");
+    }
+
+    #[test]
+    fn asterisk_form_is_left_alone() {
+        let rewrite = path_rewrite("add_prefix = \"/v2\"");
+        assert_eq!(rewrite_target("*", &rewrite), "*");
+    }
+
+    #[test]
+    fn locations_naming_the_upstream_are_pointed_at_the_public_host() {
+        let authorities = ["10.0.0.5:8080".to_owned(), "app.internal:80".to_owned()];
+        let rewrite = PathRewrite::default();
+        let location = |location| rewrite_location(location, &authorities, "www.example.com", &rewrite);
+        assert_eq!(location("http://10.0.0.5:8080/login").as_deref(), Some("http://www.example.com/login"));
+        assert_eq!(location("HTTP://APP.internal/a?b#c").as_deref(), Some("http://www.example.com/a?b#c"));
+        assert_eq!(location("http://app.internal:80").as_deref(), Some("http://www.example.com/"));
+        assert_eq!(location("http://10.0.0.5:8081/login"), None);
+        assert_eq!(location("http://elsewhere.example/login"), None);
+        assert_eq!(location("https://10.0.0.5:8080/login"), None);
+        assert_eq!(location("/login"), None);
+    }
+
+    #[test]
+    fn locations_get_back_the_prefixes_the_route_changed() {
+        let authorities = ["10.0.0.5:8080".to_owned()];
+        let rewrite = path_rewrite("strip_prefix = \"/billing/\"");
+        let location = |location| rewrite_location(location, &authorities, "example.com:8443", &rewrite);
+        assert_eq!(location("http://10.0.0.5:8080/invoices?page=2").as_deref(), Some("http://example.com:8443/billing/invoices?page=2"));
+        assert_eq!(location("http://10.0.0.5:8080/").as_deref(), Some("http://example.com:8443/billing/"));
+
+        let rewrite = path_rewrite("strip_prefix = \"/billing\"\nadd_prefix = \"/v2\"");
+        let location = |location| rewrite_location(location, &authorities, "example.com", &rewrite);
+        assert_eq!(location("http://10.0.0.5:8080/v2/invoices").as_deref(), Some("http://example.com/billing/invoices"));
+        assert_eq!(location("http://10.0.0.5:8080/v2").as_deref(), Some("http://example.com/billing/"));
+        // Outside the added prefix, only the authority is rewritten.
+        assert_eq!(location("http://10.0.0.5:8080/v20/x?q").as_deref(), Some("http://example.com/v20/x?q"));
+        assert_eq!(location("http://10.0.0.5:8080/").as_deref(), Some("http://example.com/"));
+    }
+
+    #[test]
+    fn header_rules_apply_in_order() {
+        let mut head = http::parse_head("HTTP/1.1 200 OK\r\nServer: upstream\r\nSet-Cookie: a=1\r\nX-Version: app/1.2\r\n\r\n").unwrap();
+        apply_header_rules(&mut head, &rules(r#"
+            rules = [
+                { action = "remove", name = "server" },
+                { action = "add", name = "Set-Cookie", value = "b=2" },
+                { action = "set", name = "X-Frame-Options", value = "DENY" },
+                { action = "replace", name = "X-Version", pattern = "app/(\\d+)\\..*", value = "v$1" },
+            ]
+        "#));
+        assert_eq!(head.header_values("Server").count(), 0);
+        assert_eq!(head.header_values("Set-Cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(head.header_values("X-Frame-Options").collect::<Vec<_>>(), ["DENY"]);
+        assert_eq!(head.header_values("X-Version").collect::<Vec<_>>(), ["v1"]);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{self, Config, HealthCheck, OutlierDetection, Route, Strategy};
+
+
+/// Points per server on the consistent-hashing ring; more spread the load
+/// more evenly.
+const RING_POINTS_PER_SERVER: usize = 100;
+
+/// The SplitMix64 finalizer, which spreads similar inputs across the whole
+/// range.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// 64-bit FNV-1a, mixed so that keys differing in one byte land far apart
+/// on the ring. Unlike `DefaultHasher` it is the same in every build, so
+/// several proxies hash keys to the same servers.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    mix(hash)
+}
+
+/// A pseudo-random number, good enough to spread load.
+fn random() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    static SEED: OnceLock<u64> = OnceLock::new();
+    let seed = *SEED.get_or_init(|| match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_nanos() as u64,
+        Err(_) => 0,
+    });
+    // SplitMix64.
+    mix(seed.wrapping_add(STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)))
+}
+
+/// Whether `host` matches a route's host pattern: an exact name, or
+/// `*.domain` for any subdomain of `domain`.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.');
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len() > domain.len() + 1
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.',
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Whether `path` lies under `prefix`, which only matches whole segments.
+fn path_matches(prefix: &str, path: &str) -> bool {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+
+/// One server of a backend.
+pub struct Upstream {
+    /// `host:port`, or `host` for port 80, resolved when a request is sent.
+    pub address: String,
+    /// Requests being forwarded to this server.
+    active: AtomicUsize,
+    /// Cleared while the server fails active health checks.
+    healthy: AtomicBool,
+    outlier_detection: Option<OutlierDetection>,
+    /// Consecutive live requests that have failed.
+    failures: AtomicU32,
+    /// When an ejected server comes back into rotation.
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+/// Counts a request as active on an upstream for as long as it is held.
+pub struct Lease<'a>(&'a Upstream);
+
+impl Upstream {
+    pub fn lease(&self) -> Lease<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Lease(self)
+    }
+
+    fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Whether the server is in rotation: passing health checks and not
+    /// ejected.
+    pub fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut ejected_until = match self.ejected_until.lock() {
+            Ok(ejected_until) => ejected_until,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *ejected_until = None;
+                info!("Returning {} to rotation", self.address);
+                true
+            },
+            None => true,
+        }
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Notes that a live request to the server succeeded.
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Notes that a live request to the server failed, ejecting it if that
+    /// makes too many in a row.
+    pub fn record_failure(&self) {
+        let outlier_detection = match &self.outlier_detection {
+            Some(outlier_detection) => outlier_detection,
+            None => return,
+        };
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < outlier_detection.max_failures {
+            return;
+        }
+
+        self.failures.store(0, Ordering::Relaxed);
+        let mut ejected_until = match self.ejected_until.lock() {
+            Ok(ejected_until) => ejected_until,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *ejected_until = Some(Instant::now() + Duration::from_secs(outlier_detection.ejection_time));
+        warn!("Ejecting {} for {}s after {} failures", self.address, outlier_detection.ejection_time, failures);
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// A named group of servers, and how requests are spread across them.
+pub struct Backend {
+    servers: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    /// The request header whose value is hashed under consistent hashing,
+    /// rather than the client's IP address.
+    pub hash_header: Option<String>,
+    pub health_check: Option<HealthCheck>,
+    /// Points on the consistent-hashing ring and the servers they belong
+    /// to, sorted by point.
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+}
+
+impl Backend {
+    fn new(config: &config::Backend) -> Backend {
+        let mut ring = Vec::new();
+        if config.strategy == Strategy::ConsistentHash {
+            for (index, server) in config.servers.iter().enumerate() {
+                for point in 0..RING_POINTS_PER_SERVER {
+                    ring.push((hash(format!("{}#{}", server, point).as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Backend {
+            servers: config.servers.iter()
+                .map(|address| Arc::new(Upstream {
+                    address: address.clone(),
+                    active: AtomicUsize::new(0),
+                    healthy: AtomicBool::new(true),
+                    outlier_detection: config.outlier_detection.clone(),
+                    failures: AtomicU32::new(0),
+                    ejected_until: Mutex::new(None),
+                }))
+                .collect(),
+            strategy: config.strategy,
+            hash_header: config.hash_header.clone(),
+            health_check: config.health_check.clone(),
+            ring,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The servers in rotation, in the order they should be tried: the one
+    /// the strategy picks first, then the others to fail over to. If none are
+    /// in rotation, every server is tried rather than none.
+    ///
+    /// `key` is what consistent hashing hashes; other strategies ignore it.
+    pub fn candidates(&self, key: &str) -> Vec<&Upstream> {
+        let order = self.order(key);
+        let available: Vec<&Upstream> = order.iter()
+            .map(|&index| &*self.servers[index])
+            .filter(|upstream| upstream.is_available())
+            .collect();
+        if !available.is_empty() {
+            return available;
+        }
+        order.into_iter().map(|index| &*self.servers[index]).collect()
+    }
+
+    /// Every server, as indices in the order the strategy prefers them.
+    fn order(&self, key: &str) -> Vec<usize> {
+        let count = self.servers.len();
+        match self.strategy {
+            Strategy::RoundRobin => {
+                let first = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|offset| (first + offset) % count).collect()
+            },
+            Strategy::LeastConnections => {
+                // Ties go round-robin, so idle servers share the load.
+                let first = self.next.fetch_add(1, Ordering::Relaxed);
+                let mut order: Vec<usize> = (0..count).map(|offset| (first + offset) % count).collect();
+                order.sort_by_key(|&index| self.servers[index].active());
+                order
+            },
+            Strategy::RandomTwoChoices => {
+                let mut order: Vec<usize> = (0..count).collect();
+                if count > 1 {
+                    let a = (random() % count as u64) as usize;
+                    let b = (a + 1 + (random() % (count as u64 - 1)) as usize) % count;
+                    let chosen = if self.servers[b].active() < self.servers[a].active() { b } else { a };
+                    order.swap(0, chosen);
+                }
+                order
+            },
+            Strategy::ConsistentHash => {
+                let key_hash = hash(key.as_bytes());
+                let start = self.ring.partition_point(|&(point, _)| point < key_hash);
+                let mut order = Vec::with_capacity(count);
+                for &(_, index) in self.ring[start..].iter().chain(&self.ring[..start]) {
+                    if !order.contains(&index) {
+                        order.push(index);
+                        if order.len() == count {
+                            break;
+                        }
+                    }
+                }
+                order
+            },
+        }
+    }
+
+    /// Every server, for health checking.
+    pub fn servers(&self) -> &[Arc<Upstream>] {
+        &self.servers
+    }
+}
+
+
+/// The routing table of reverse-proxy mode.
+pub struct Router {
+    routes: Vec<Route>,
+    backends: BTreeMap<String, Backend>,
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Router {
+        Router {
+            routes: config.routes.clone(),
+            backends: config.backends.iter()
+                .map(|(name, backend)| (name.clone(), Backend::new(backend)))
+                .collect(),
+        }
+    }
+
+    /// Every backend, with its name.
+    pub fn backends(&self) -> impl Iterator<Item = (&str, &Backend)> {
+        self.backends.iter().map(|(name, backend)| (name.as_str(), backend))
+    }
+
+    /// Whether any routes are configured, making this a reverse proxy.
+    pub fn is_enabled(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    /// The first route matching `host` and `path`.
+    pub fn route(&self, host: &str, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| {
+            route.host.as_deref().is_none_or(|pattern| host_matches(pattern, host))
+                && path_matches(&route.path, path)
+        })
+    }
+
+    /// The backend a route sends requests to, unless it redirects them.
+    pub fn backend(&self, route: &Route) -> Option<&Backend> {
+        // Routes to unknown backends are rejected when loading the configuration.
+        self.backends.get(route.backend.as_deref()?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(text: &str) -> Router {
+        Router::new(&toml::from_str::<Config>(text).unwrap())
+    }
+
+    fn backend(strategy: &str, servers: &[&str]) -> Backend {
+        let config: config::Backend = toml::from_str(&format!("servers = {:?}\nstrategy = {:?}", servers, strategy)).unwrap();
+        Backend::new(&config)
+    }
+
+    fn addresses(candidates: Vec<&Upstream>) -> Vec<&str> {
+        candidates.into_iter().map(|upstream| upstream.address.as_str()).collect()
+    }
+
+    #[test]
+    fn round_robin_rotates_through_every_server() {
+        let backend = backend("round-robin", &["a:80", "b:80", "c:80"]);
+        assert_eq!(addresses(backend.candidates("")), ["a:80", "b:80", "c:80"]);
+        assert_eq!(addresses(backend.candidates("")), ["b:80", "c:80", "a:80"]);
+        assert_eq!(addresses(backend.candidates("")), ["c:80", "a:80", "b:80"]);
+        assert_eq!(addresses(backend.candidates("")), ["a:80", "b:80", "c:80"]);
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_servers() {
+        let backend = backend("least-connections", &["a:80", "b:80"]);
+        let _lease = backend.servers()[0].lease();
+        for _ in 0..4 {
+            assert_eq!(addresses(backend.candidates("")), ["b:80", "a:80"]);
+        }
+    }
+
+    #[test]
+    fn consistent_hash_is_stable_and_covers_every_server() {
+        let servers = ["a:80", "b:80", "c:80", "d:80"];
+        let backend_one = backend("consistent-hash", &servers);
+        let backend_two = backend("consistent-hash", &servers);
+        let mut firsts = Vec::new();
+        for client in 0..200 {
+            let key = format!("10.0.0.{}", client);
+            let order = addresses(backend_one.candidates(&key));
+            assert_eq!(order, addresses(backend_one.candidates(&key)));
+            assert_eq!(order, addresses(backend_two.candidates(&key)));
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, servers);
+            if !firsts.contains(&order[0]) {
+                firsts.push(order[0]);
+            }
+        }
+        assert_eq!(firsts.len(), servers.len());
+    }
+
+    #[test]
+    fn hash_is_deterministic() {
+        assert_eq!(hash(b"10.0.0.1"), hash(b"10.0.0.1"));
+        assert_ne!(hash(b"10.0.0.1"), hash(b"10.0.0.2"));
+    }
+
+    #[test]
+    fn unavailable_servers_are_skipped_unless_all_are() {
+        let backend = backend("round-robin", &["a:80", "b:80", "c:80"]);
+        backend.servers()[1].set_healthy(false);
+        for _ in 0..3 {
+            let candidates = addresses(backend.candidates(""));
+            assert_eq!(candidates.len(), 2);
+            assert!(!candidates.contains(&"b:80"));
+        }
+
+        for server in backend.servers() {
+            server.set_healthy(false);
+        }
+        assert_eq!(addresses(backend.candidates("")).len(), 3);
+    }
+
+    #[test]
+    fn hosts_match_exactly_or_by_subdomain() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches("example.com", "EXAMPLE.com."));
+        assert!(!host_matches("example.com", "www.example.com"));
+        assert!(host_matches("*.example.com", "www.example.com"));
+        assert!(host_matches("*.example.com", "a.b.Example.COM"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", ".example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn paths_match_whole_segments() {
+        assert!(path_matches("/api", "/api"));
+        assert!(path_matches("/api", "/api/users"));
+        assert!(path_matches("/api", "/api?q=1"));
+        assert!(!path_matches("/api", "/apis"));
+        assert!(!path_matches("/api", "/v1/api"));
+        assert!(path_matches("/api/", "/api/users"));
+        assert!(!path_matches("/api/", "/api"));
+        assert!(path_matches("/", "/anything"));
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let router = router(r#"
+            [backends.a]
+            servers = ["10.0.0.1:80"]
+            [backends.b]
+            servers = ["10.0.0.2:80"]
+            [backends.c]
+            servers = ["10.0.0.3:80"]
+            [[routes]]
+            host = "*.example.com"
+            path = "/api"
+            backend = "a"
+            [[routes]]
+            host = "example.com"
+            backend = "b"
+            [[routes]]
+            path = "/static"
+            backend = "c"
+        "#);
+        let backend = |host, path| router.route(host, path).and_then(|route| route.backend.as_deref());
+        assert_eq!(backend("www.example.com", "/api/users"), Some("a"));
+        assert_eq!(backend("www.example.com", "/apis"), None);
+        assert_eq!(backend("example.com", "/api/users"), Some("b"));
+        assert_eq!(backend("other.test", "/static/app.js"), Some("c"));
+        assert_eq!(backend("www.example.com", "/static/app.js"), Some("c"));
+        assert_eq!(backend("other.test", "/"), None);
+    }
+}
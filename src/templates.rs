@@ -0,0 +1,244 @@
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::exchange::Exchange;
+use crate::http;
+use crate::timestamp;
+
+
+/// Whether the client would rather have JSON than HTML, judging by the
+/// quality values in its `Accept` header. Wildcards favour neither.
+fn prefers_json(accept: Option<&str>) -> bool {
+    let mut json_quality = 0.0;
+    let mut html_quality = 0.0;
+
+    for media_range in accept.unwrap_or("").split(',') {
+        let mut parameters = media_range.split(';').map(str::trim);
+        let media_type = parameters.next().unwrap_or("").to_ascii_lowercase();
+        let quality = parameters
+            .filter_map(|parameter| parameter.strip_prefix("q="))
+            .filter_map(|quality| quality.parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            json_quality = f32::max(json_quality, quality);
+        } else if media_type == "text/html" {
+            html_quality = f32::max(html_quality, quality);
+        }
+    }
+
+    json_quality > html_quality
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_header(value: &str) -> String {
+    value.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Replaces each `{{name}}` in `text` with the matching value, escaped for
+/// its surroundings. Unknown placeholders are left as they are.
+fn substitute(text: &str, values: &[(&str, String)], escape: fn(&str) -> String) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => {
+                rest = &rest[start..];
+                break;
+            },
+        };
+        let name = after[..end].trim();
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => result.push_str(&escape(value)),
+            None => result.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Renders a template file (a complete HTTP response, with placeholders) into
+/// the bytes to send. `Content-Length` is always computed, never taken from
+/// the template.
+fn render(template: &[u8], values: &[(&str, String)]) -> Result<Vec<u8>, String> {
+    let template = String::from_utf8_lossy(template);
+    let (head, body) = match template.split_once("\r\n\r\n").or_else(|| template.split_once("\n\n")) {
+        Some(parts) => parts,
+        None => (template.trim_end(), ""),
+    };
+
+    let head = substitute(head, values, escape_header);
+    let mut head = match http::parse_head(&head) {
+        Ok(head) => head,
+        Err(err) => return Err(err.to_string()),
+    };
+
+    let content_type = head.header_values("Content-Type").next().unwrap_or("").to_ascii_lowercase();
+    let escape = if content_type.contains("json") {
+        escape_json
+    } else if content_type.contains("html") || content_type.contains("xml") {
+        escape_html
+    } else {
+        escape_header
+    };
+    let body = substitute(body, values, escape).into_bytes();
+
+    head.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+    head.set_header("Content-Length", &body.len().to_string());
+    // Error responses always end the connection.
+    head.set_header("Connection", "close");
+
+    let mut response = head.to_bytes();
+    response.extend_from_slice(&body);
+    Ok(response)
+}
+
+/// A bare response for when no template can be used.
+fn fallback(status: u16) -> Vec<u8> {
+    let reason = http::reason_phrase(status);
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=UTF-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status, reason, reason.len() + 1, reason,
+    ).into_bytes()
+}
+
+/// Renders the error response for `status` from the templates in `directory`.
+///
+/// `error<status>.http` is used, falling back to the generic `error.http`.
+/// Clients that prefer JSON get `error<status>.json.http` or `error.json.http`
+/// instead, where those exist. Templates may use the placeholders `{{status}}`,
+/// `{{reason}}`, `{{host}}`, `{{request_id}}` and `{{timestamp}}`.
+pub async fn render_error(directory: &Path, status: u16, exchange: &Exchange) -> Vec<u8> {
+    let mut names = Vec::new();
+    if prefers_json(exchange.accept.as_deref()) {
+        names.push(format!("error{}.json.http", status));
+        names.push("error.json.http".to_owned());
+    }
+    names.push(format!("error{}.http", status));
+    names.push("error.http".to_owned());
+
+    let values = [
+        ("status", status.to_string()),
+        ("reason", http::reason_phrase(status).to_owned()),
+        ("host", exchange.host.clone().unwrap_or_default()),
+        ("request_id", exchange.id.clone()),
+        ("timestamp", timestamp::rfc3339(SystemTime::now())),
+    ];
+
+    for name in names {
+        let path = directory.join(&name);
+        let template = match tokio::fs::read(&path).await {
+            Ok(template) => template,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                warn!("Could not read {}: {}", path.display(), err);
+                continue;
+            },
+        };
+        match render(&template, &values) {
+            Ok(response) => return response,
+            Err(err) => warn!("Could not render {}: {}", path.display(), err),
+        }
+    }
+
+    fallback(status)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<(&'static str, String)> {
+        vec![
+            ("status", "404".to_owned()),
+            ("host", "<a href=\"x\">&'\\\r\n".to_owned()),
+        ]
+    }
+
+    fn render_text(template: &str) -> String {
+        String::from_utf8(render(template.as_bytes(), &values()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn unknown_placeholders_are_left_as_they_are() {
+        let values = values();
+        assert_eq!(substitute("{{status}} {{ status }} {{nope}}", &values, escape_header), "404 404 {{nope}}");
+        assert_eq!(substitute("a {{status}} {{status", &values, escape_header), "a 404 {{status");
+        assert_eq!(substitute("{{", &values, escape_header), "{{");
+        assert_eq!(substitute("}} {}", &values, escape_header), "}} {}");
+    }
+
+    #[test]
+    fn values_are_escaped_for_the_content_type() {
+        let html = render_text("HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\n\r\n<p>{{host}}</p>");
+        assert!(html.ends_with("\r\n\r\n<p>&lt;a href=&quot;x&quot;&gt;&amp;&#39;\\\r\n</p>"), "{}", html);
+
+        let json = render_text("HTTP/1.1 404 Not Found\r\nContent-Type: application/problem+json\r\n\r\n{\"host\": \"{{host}}\"}");
+        assert!(json.ends_with("\r\n\r\n{\"host\": \"<a href=\\\"x\\\">&'\\\\\\r\\n\"}"), "{}", json);
+
+        let text = render_text("HTTP/1.1 404 Not Found\r\nX-Host: {{host}}\r\n\r\n{{host}}");
+        assert!(text.contains("\r\nX-Host: <a href=\"x\">&'\\\r\n"), "{}", text);
+        assert!(text.ends_with("\r\n\r\n<a href=\"x\">&'\\"), "{}", text);
+    }
+
+    #[test]
+    fn content_length_is_computed() {
+        let response = render_text("HTTP/1.1 {{status}} Not Found\nContent-Length: 999\nContent-Length: 1\n\nGone: {{status}}\n");
+        let head = http::parse_head(&response).unwrap();
+        assert_eq!(head.start_line, "HTTP/1.1 404 Not Found");
+        assert_eq!(head.header_values("Content-Length").collect::<Vec<_>>(), ["10"]);
+        assert_eq!(head.header_values("Connection").collect::<Vec<_>>(), ["close"]);
+        assert!(response.ends_with("\r\n\r\nGone: 404\n"));
+    }
+
+    #[test]
+    fn json_is_preferred_only_when_rated_higher() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("Application/Problem+JSON")));
+        assert!(!prefers_json(Some("text/html, application/json")));
+        assert!(!prefers_json(Some("text/html, application/json;q=0.9")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json; q=0.8")));
+        assert!(!prefers_json(Some("application/json;q=0")));
+    }
+}
@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Instant, Sleep};
+
+
+/// A deadline that starts when an operation first has to wait, and is
+/// disarmed as soon as it makes progress.
+struct Deadline {
+    timeout: Option<Duration>,
+    sleep: Pin<Box<Sleep>>,
+    armed: bool,
+}
+
+impl Deadline {
+    fn new(timeout: Option<Duration>) -> Deadline {
+        Deadline {
+            timeout,
+            sleep: Box::pin(time::sleep(Duration::ZERO)),
+            armed: false,
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.armed = false;
+    }
+
+    fn poll_progress<T>(&mut self, cx: &mut Context<'_>, progress: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if progress.is_ready() {
+            self.armed = false;
+            return progress;
+        }
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+
+        if !self.armed {
+            self.sleep.as_mut().reset(Instant::now() + timeout);
+            self.armed = true;
+        }
+        match self.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.armed = false;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+/// Wraps a stream so that reads and writes fail with `TimedOut` when they
+/// make no progress for the configured time, like the socket timeouts of
+/// `std::net::TcpStream`.
+pub struct TimeoutStream<S> {
+    inner: S,
+    read: Deadline,
+    write: Deadline,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(inner: S, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> TimeoutStream<S> {
+        TimeoutStream {
+            inner,
+            read: Deadline::new(read_timeout),
+            write: Deadline::new(write_timeout),
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read.set_timeout(timeout);
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let progress = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.read.poll_progress(cx, progress)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let progress = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.write.poll_progress(cx, progress)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let progress = Pin::new(&mut this.inner).poll_flush(cx);
+        this.write.poll_progress(cx, progress)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let progress = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.write.poll_progress(cx, progress)
+    }
+}
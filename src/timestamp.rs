@@ -0,0 +1,95 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// A point in time broken down into UTC calendar fields.
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    fn from_system_time(time: SystemTime) -> DateTime {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        let days = seconds.div_euclid(86_400);
+        let time_of_day = seconds.rem_euclid(86_400) as u32;
+
+        // Converts days since the epoch to a proleptic Gregorian date, after
+        // Howard Hinnant's `civil_from_days`.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: time_of_day / 3600,
+            minute: time_of_day / 60 % 60,
+            second: time_of_day % 60,
+        }
+    }
+}
+
+
+/// Formats `time` as an RFC 3339 timestamp in UTC, e.g. `2025-03-01T12:00:00Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let t = DateTime::from_system_time(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", t.year, t.month, t.day, t.hour, t.minute, t.second)
+}
+
+/// Formats `time` as in the Common Log Format, e.g. `01/Mar/2025:12:00:00 +0000`.
+pub fn common_log(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let t = DateTime::from_system_time(time);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        t.day, MONTHS[t.month as usize - 1], t.year, t.hour, t.minute, t.second,
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn at(seconds: i64) -> SystemTime {
+        match seconds {
+            0.. => UNIX_EPOCH + Duration::from_secs(seconds as u64),
+            _ => UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()),
+        }
+    }
+
+    #[test]
+    fn known_dates_are_formatted() {
+        assert_eq!(rfc3339(at(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(at(-1)), "1969-12-31T23:59:59Z");
+        assert_eq!(rfc3339(at(1_735_689_599)), "2024-12-31T23:59:59Z");
+        assert_eq!(common_log(at(1_740_830_400)), "01/Mar/2025:12:00:00 +0000");
+        assert_eq!(common_log(at(0)), "01/Jan/1970:00:00:00 +0000");
+    }
+
+    #[test]
+    fn leap_days_are_counted() {
+        assert_eq!(rfc3339(at(951_782_400)), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(at(1_709_210_096)), "2024-02-29T12:34:56Z");
+        assert_eq!(common_log(at(1_709_210_096)), "29/Feb/2024:12:34:56 +0000");
+        // 2100 is not a leap year.
+        assert_eq!(rfc3339(at(4_107_542_400 - 1)), "2100-02-28T23:59:59Z");
+        assert_eq!(rfc3339(at(4_107_542_400)), "2100-03-01T00:00:00Z");
+    }
+}